@@ -1,49 +1,253 @@
+use std::env;
+use std::fs;
 use std::io::{self, BufRead, Write};
+use std::path::Path;
 use std::process::exit;
 
 mod query;
 mod storage;
 mod types;
 
-use crate::query::sql::{parse_statement, Statement};
-use crate::storage::btree::{self, BTree};
+use crate::query::sql::{
+    format_timestamp, parse_csv_row, parse_statement, AsOf, OnConflict, Statement,
+};
+use crate::storage::btree::{self, BTree, BTreeError, PagerError, Retention};
 use crate::types::values::*;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 enum MetaCommand {
     Exit,
+    Stats,
+    Vacuum,
+    Import(String),
+    Snapshots,
 }
 
 fn parse_meta(cmd: &str) -> Option<MetaCommand> {
-    match cmd {
-        ".exit" => Some(MetaCommand::Exit),
-        _ => None,
+    let mut itr = cmd.split_whitespace();
+    let meta = match itr.next()? {
+        ".exit" => MetaCommand::Exit,
+        ".stats" => MetaCommand::Stats,
+        ".vacuum" => MetaCommand::Vacuum,
+        ".snapshots" => MetaCommand::Snapshots,
+        ".import" => MetaCommand::Import(itr.next()?.to_string()),
+        _ => return None,
+    };
+    match itr.next() {
+        None => Some(meta),
+        Some(_) => None,
     }
 }
 
-fn do_meta(cmd: MetaCommand) {
+fn do_meta(bt: &mut BTree<i32, Vec<Value>>, path: &str, cmd: MetaCommand) {
     match cmd {
         MetaCommand::Exit => {
+            if bt.in_transaction() {
+                match bt.rollback() {
+                    Ok(()) => println!("open transaction rolled back"),
+                    Err(err) => eprintln!("error: could not roll back transaction: {}", err),
+                }
+            }
+            if let Err(err) = bt.checkpoint() {
+                eprintln!("error: could not checkpoint database: {}", err);
+                exit(1);
+            }
             exit(0);
         }
+        MetaCommand::Stats => {
+            let stats = bt.pool_stats();
+            println!(
+                "buffer pool: {} hits, {} misses, {} evictions, {} writes",
+                stats.hits, stats.misses, stats.evictions, stats.writes
+            );
+            match bt.key_stats() {
+                Ok(keys) => println!(
+                    "keys: {} bytes in leaves, {} saved by prefix compression; \
+                     {} bytes in separators, {} saved by truncation",
+                    keys.leaf_bytes, keys.prefix_saved, keys.separator_bytes, keys.separator_saved
+                ),
+                Err(err) => println!("Error: {}", err),
+            }
+        }
+        MetaCommand::Vacuum => do_vacuum(bt),
+        MetaCommand::Import(path) => match do_import(bt, &path) {
+            Ok(n_rows) => println!("{} rows imported", n_rows),
+            Err(err) => println!("Error: {}", err),
+        },
+        MetaCommand::Snapshots => do_snapshots(bt, path),
+    }
+}
+
+// List the commits the table can still be read at with select ... as of.
+// Only databases created with --shadow keep them.
+fn do_snapshots(bt: &BTree<i32, Vec<Value>>, path: &str) {
+    let commits = bt.commits();
+    if commits.is_empty() {
+        println!("Error: .snapshots needs a database created with --shadow");
+        return;
+    }
+    println!("{0: <5} | {1: <19} | rows", "id", "committed at (UTC)");
+    for commit in commits {
+        let rows =
+            BTree::<i32, Vec<Value>>::open_shadow_at(path, commit.id).and_then(|old| old.count());
+        match rows {
+            Ok(n_rows) => println!(
+                "{0: <5} | {1: <19} | {2}",
+                commit.id,
+                format_timestamp(commit.time),
+                n_rows
+            ),
+            Err(err) => println!("Error: {}", err),
+        }
     }
 }
 
-fn do_select(bt: &BTree<i32, Vec<Value>>, stmt: Statement<i32, Vec<Value>>) -> Vec<Vec<Value>> {
-    match stmt {
-        Statement::SelectAll => bt
-            .find_range(&i32::MIN, &i32::MAX)
-            .into_iter()
-            .map(|(k, v)| vec![Value::Int(k), v[0].clone(), v[1].clone()])
-            .collect(),
-        Statement::SelectOne(k) => match bt.find(&k) {
+// Open the table read-only as it was at an earlier commit. Only databases
+// created with --shadow keep them.
+fn open_as_of(
+    bt: &BTree<i32, Vec<Value>>,
+    path: &str,
+    at: AsOf,
+) -> anyhow::Result<BTree<i32, Vec<Value>>> {
+    if bt.commits().is_empty() {
+        anyhow::bail!("select ... as of needs a database created with --shadow");
+    }
+    let id = match at {
+        AsOf::Commit(id) => id,
+        AsOf::Time(time) => match bt.commits().iter().rev().find(|c| c.time <= time) {
+            Some(commit) => commit.id,
+            None => anyhow::bail!("no snapshot is kept from {}", format_timestamp(time)),
+        },
+    };
+    Ok(BTree::open_shadow_at(path, id)?)
+}
+
+// leave some room in the pages of an imported table for later inserts
+const IMPORT_FILL: f64 = 0.9;
+
+// Import rows from a CSV file. The first line is skipped if it's a header.
+fn do_import(bt: &mut BTree<i32, Vec<Value>>, path: &str) -> anyhow::Result<usize> {
+    let text = fs::read_to_string(path)?;
+    let mut rows = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_csv_row(line) {
+            Some(row) => rows.push(row),
+            None if i == 0 => continue,
+            None => anyhow::bail!("line {} couldn't be parsed", i + 1),
+        }
+    }
+    rows.sort_by_key(|(k, _)| *k);
+    let n_rows = rows.len();
+
+    // an empty table is built bottom up, otherwise rows are inserted
+    match bt.bulk_load(rows.iter().cloned(), IMPORT_FILL) {
+        Err(BTreeError::NotEmpty) => {
+            // the rows go in together, in a transaction of their own unless
+            // one is already open
+            let own_txn = !bt.in_transaction();
+            if own_txn {
+                bt.begin()?;
+            }
+            for (k, v) in rows {
+                if let Err(err) = bt.insert(k, v) {
+                    if own_txn {
+                        bt.rollback()?;
+                    }
+                    return Err(err.into());
+                }
+            }
+            if own_txn {
+                bt.commit()?;
+            }
+        }
+        res => res?,
+    }
+    bt.flush()?;
+    Ok(n_rows)
+}
+
+fn do_insert(
+    bt: &mut BTree<i32, Vec<Value>>,
+    k: i32,
+    v: Vec<Value>,
+    on_conflict: OnConflict,
+) -> Result<(), BTreeError> {
+    match on_conflict {
+        OnConflict::Error => bt.insert(k, v),
+        OnConflict::DoNothing => match bt.insert(k, v) {
+            Err(BTreeError::DuplicateKey) => Ok(()),
+            res => res,
+        },
+        OnConflict::DoUpdate => bt.upsert(k, v).map(|_| ()),
+    }
+}
+
+fn do_vacuum(bt: &mut BTree<i32, Vec<Value>>) {
+    match bt.rebuild().and_then(|n| bt.flush().map(|_| n)) {
+        Ok(n_pages) => println!("{} pages reclaimed", n_pages),
+        Err(err) => println!("Error: {}", err),
+    }
+}
+
+// Print the rows or count read by a select.
+fn do_read(bt: &BTree<i32, Vec<Value>>, stmt: Statement<i32, Vec<Value>>) {
+    if let Statement::Count = stmt {
+        match bt.count() {
+            Ok(n_rows) => println!("{}", n_rows),
+            Err(err) => println!("Error: {}", err),
+        }
+        return;
+    }
+    println!("{0: <5} | {1: <32} | {2: <32}", "id", "username", "email");
+    match do_select(bt, stmt) {
+        Ok(rows) => {
+            for r in rows.iter() {
+                println!("{0: <5} | {1: <32} | {2: <32}", r[0], r[1], r[2]);
+            }
+        }
+        Err(err) => println!("Error: {}", err),
+    }
+}
+
+fn do_select(
+    bt: &BTree<i32, Vec<Value>>,
+    stmt: Statement<i32, Vec<Value>>,
+) -> Result<Vec<Vec<Value>>, BTreeError> {
+    let rows = match stmt {
+        Statement::SelectAll {
+            desc,
+            limit,
+            offset,
+        } => {
+            // stream rows from a cursor so a limit stops the scan early, and
+            // jump past the offset rows by their position
+            let mut rows = vec![];
+            let mut cursor = bt.cursor();
+            let n_rows = bt.count()?;
+            let mut valid = match offset.checked_add(1).and_then(|o| n_rows.checked_sub(o)) {
+                Some(last) if desc => cursor.seek_nth(last)?,
+                Some(_) => cursor.seek_nth(offset)?,
+                None => false,
+            };
+            while valid && limit.is_none_or(|n| rows.len() < n) {
+                let (k, v) = (cursor.key().unwrap(), cursor.val().unwrap());
+                rows.push(vec![Value::Int(*k), v[0].clone(), v[1].clone()]);
+                valid = if desc { cursor.prev()? } else { cursor.next()? };
+            }
+            rows
+        }
+        Statement::SelectOne(k) => match bt.find(&k)? {
             Some(vs) => {
                 vec![vec![Value::Int(k), vs[0].clone(), vs[1].clone()]]
             }
             None => vec![],
         },
         _ => vec![],
-    }
+    };
+    Ok(rows)
 }
 
 // HARDCODED TABLE
@@ -53,45 +257,180 @@ fn do_select(bt: &BTree<i32, Vec<Value>>, stmt: Statement<i32, Vec<Value>>) -> V
 
 // insert 1 'meiji163' 'meiji163@github.com'
 
+// pages split when they run out of bytes well before reaching the fanout,
+// so rows pack as densely as they fit
+const FANOUT: usize = 4095;
+
+const USAGE: &str = "\
+usage: bokedb [--shadow] [--retain-commits N] [--retain-secs N] [path]
+
+  --shadow            create the database copy-on-write, so that earlier
+                      commits can be listed with .snapshots and read with
+                      select ... as of
+  --retain-commits N  keep at least the last N commits of a copy-on-write
+                      database readable (default 100)
+  --retain-secs N     keep the commits of a copy-on-write database made in
+                      the last N seconds readable (default 86400)";
+
+// The command line options.
+struct Args {
+    path: String,
+    shadow: bool,
+    retention: Retention,
+}
+
+// Parse the command line arguments, or return None if they aren't valid.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut path = None;
+    let mut shadow = false;
+    // by default the commits of the last day are kept, and at least the last 100
+    let mut retention = Retention {
+        commits: 100,
+        secs: 24 * 60 * 60,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--shadow" => shadow = true,
+            "--retain-commits" => retention.commits = args.next()?.parse().ok()?,
+            "--retain-secs" => retention.secs = args.next()?.parse().ok()?,
+            _ if arg.starts_with('-') || path.is_some() => return None,
+            _ => path = Some(arg),
+        }
+    }
+    Some(Args {
+        path: path.unwrap_or_else(|| "boke.db".to_string()),
+        shadow,
+        retention,
+    })
+}
+
+// Open the database at path. An existing file is opened in the mode it was
+// created in, and a new one is created copy-on-write if shadow is set.
+// Commits of copy-on-write databases are kept as long as the retention says.
+fn open_db(
+    path: &str,
+    shadow: bool,
+    retention: Retention,
+) -> Result<BTree<i32, Vec<Value>>, BTreeError> {
+    let exists = Path::new(path).metadata().is_ok_and(|m| m.len() > 0);
+    if exists || shadow {
+        match BTree::open_shadow(path, FANOUT, true, retention) {
+            Err(BTreeError::Pager(PagerError::NotCopyOnWrite)) if exists => {}
+            res => return res,
+        }
+    }
+    BTree::open(path, FANOUT, true)
+}
+
 fn main() -> io::Result<()> {
-    let mut bt: btree::BTree<i32, Vec<Value>> = btree::BTree::new(101, true);
+    if env::args().skip(1).any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        exit(0);
+    }
+    let Some(Args {
+        path,
+        shadow,
+        retention,
+    }) = parse_args(env::args().skip(1))
+    else {
+        eprintln!("{}", USAGE);
+        exit(2);
+    };
+    let mut bt: btree::BTree<i32, Vec<Value>> = match open_db(&path, shadow, retention) {
+        Ok(bt) => bt,
+        Err(err) => {
+            eprintln!("error: could not open database `{}`: {}", path, err);
+            exit(1);
+        }
+    };
 
     let mut input_buf = String::with_capacity(4096);
     let mut stdin = io::stdin().lock();
 
     loop {
         input_buf.clear();
-        print!("db> ");
+        // the prompt shows when a transaction is open
+        print!("{}", if bt.in_transaction() { "db*> " } else { "db> " });
         io::stdout().flush().unwrap();
 
-        stdin.read_line(&mut input_buf)?;
+        if stdin.read_line(&mut input_buf)? == 0 {
+            // end of input
+            println!();
+            do_meta(&mut bt, &path, MetaCommand::Exit);
+        }
         let input = input_buf.as_str().trim();
         if input.starts_with('.') {
-            match parse_meta(&input) {
-                Some(cmd) => do_meta(cmd),
+            match parse_meta(input) {
+                Some(cmd) => do_meta(&mut bt, &path, cmd),
                 None => println!("error: meta command `{}` not recognized", input),
             }
         } else {
-            match parse_statement(&input) {
+            match parse_statement(input) {
                 Some(stmt) => match stmt {
-                    Statement::SelectAll | Statement::SelectOne(_) => {
-                        println!("{0: <5} | {1: <32} | {2: <32}", "id", "username", "email");
-                        let rows = do_select(&bt, stmt);
-                        for r in rows.iter() {
-                            println!("{0: <5} | {1: <32} | {2: <32}", r[0], r[1], r[2]);
-                        }
+                    Statement::SelectAll { .. } | Statement::SelectOne(_) | Statement::Count => {
+                        do_read(&bt, stmt)
                     }
-                    Statement::Insert((k, v)) => match bt.insert(k, v) {
-                        Ok(()) => println!("OK"),
+                    Statement::AsOf(stmt, at) => match open_as_of(&bt, &path, at) {
+                        Ok(old) => do_read(&old, *stmt),
                         Err(err) => println!("Error: {}", err),
                     },
-                    Statement::Delete(k) => match bt.delete(&k) {
+                    Statement::Insert((k, v), on_conflict) => {
+                        match do_insert(&mut bt, k, v, on_conflict).and_then(|_| bt.flush()) {
+                            Ok(()) => println!("OK"),
+                            Err(err) => println!("Error: {}", err),
+                        }
+                    }
+                    Statement::Update { key, col, val } => {
+                        let res = bt.modify(&key, |row| row[col] = val.clone());
+                        match res.and_then(|n| bt.flush().map(|_| n)) {
+                            Ok(n_rows) => println!("{} rows updated", n_rows),
+                            Err(BTreeError::KeyNotFound) => println!("row not found"),
+                            Err(err) => println!("Error: {}", err),
+                        }
+                    }
+                    Statement::Delete(k) => match bt.delete(&k).and_then(|n| bt.flush().map(|_| n))
+                    {
                         Ok(n_rows) => {
                             println!("{} rows deleted", n_rows);
                         }
-                        Err(_) => {
+                        Err(BTreeError::KeyNotFound) => {
                             println!("row not found");
                         }
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Statement::DeleteRange(min, max) => {
+                        match bt
+                            .delete_range(&min, &max)
+                            .and_then(|n| bt.flush().map(|_| n))
+                        {
+                            Ok(n_rows) => println!("{} rows deleted", n_rows),
+                            Err(err) => println!("Error: {}", err),
+                        }
+                    }
+                    Statement::Vacuum => do_vacuum(&mut bt),
+                    Statement::Begin => match bt.begin() {
+                        Ok(()) => println!("transaction started"),
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Statement::Commit => match bt.commit() {
+                        Ok(()) => println!("transaction committed"),
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Statement::Rollback => match bt.rollback() {
+                        Ok(()) => println!("transaction rolled back"),
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Statement::Savepoint(name) => match bt.savepoint(&name) {
+                        Ok(()) => println!("savepoint `{}` set", name),
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Statement::RollbackTo(name) => match bt.rollback_to(&name) {
+                        Ok(()) => println!("rolled back to savepoint `{}`", name),
+                        Err(err) => println!("Error: {}", err),
+                    },
+                    Statement::Release(name) => match bt.release(&name) {
+                        Ok(()) => println!("savepoint `{}` released", name),
+                        Err(err) => println!("Error: {}", err),
                     },
                 },
                 None => println!("error: statement couldn't be parsed"),
@@ -7,9 +7,51 @@ pub mod sql {
     #[derive(Debug, Clone)]
     pub enum Statement<K: btree::Key, V: btree::Val> {
         SelectOne(K),
-        SelectAll,
+        // in ascending or descending key order, optionally limited to a number
+        // of rows after skipping offset rows
+        SelectAll {
+            desc: bool,
+            limit: Option<usize>,
+            offset: usize,
+        },
+        Count,
         Delete(K),
-        Insert((K, V)),
+        // set column col of the row with key to val
+        Update {
+            key: K,
+            col: usize,
+            val: Value,
+        },
+        // delete the rows with min <= key <= max
+        DeleteRange(K, K),
+        Insert((K, V), OnConflict),
+        Vacuum,
+        Begin,
+        Commit,
+        Rollback,
+        Savepoint(String),
+        // undo the changes made since the named savepoint
+        RollbackTo(String),
+        // forget the named savepoint, keeping its changes
+        Release(String),
+        // a select that reads the table as it was at an earlier commit
+        AsOf(Box<Statement<K, V>>, AsOf),
+    }
+
+    // The commit a select ... as of reads at: the one with an id, or the
+    // latest made by a time in seconds since the Unix epoch.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AsOf {
+        Commit(u64),
+        Time(u64),
+    }
+
+    // What an insert does when the key is already present.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum OnConflict {
+        Error,
+        DoNothing,
+        DoUpdate,
     }
 
     // HARDCODED TABLE
@@ -18,9 +60,26 @@ pub mod sql {
     // email:    varchar(255)
 
     lazy_static! {
-        static ref INSERT_RE: Regex = Regex::new(r"^insert\s+(-?\d+)\s+'(.*)'\s+'(.*)'$").unwrap();
-        static ref SELECT_RE: Regex = Regex::new(r"^select\s+(-?\d+|\*)$").unwrap();
-        static ref DELETE_RE: Regex = Regex::new(r"^delete\s+(-?\d+)$").unwrap();
+        static ref INSERT_RE: Regex = Regex::new(
+            r"^insert\s+(-?\d+)\s+'(.*?)'\s+'(.*?)'(?:\s+on\s+conflict\s+do\s+(nothing|update))?$"
+        )
+        .unwrap();
+        static ref UPDATE_RE: Regex =
+            Regex::new(r"^update\s+(-?\d+)\s+set\s+(username|email)\s*=\s*'(.*)'$").unwrap();
+        static ref SELECT_RE: Regex = Regex::new(
+            r"^select\s+(?:(-?\d+)|(count\(\*\))|\*(?:\s+order\s+by\s+id(?:\s+(asc|desc))?)?(?:\s+limit\s+(\d+))?(?:\s+offset\s+(\d+))?)$"
+        )
+        .unwrap();
+        static ref DELETE_RE: Regex =
+            Regex::new(r"^delete\s+(?:(-?\d+)|between\s+(-?\d+)\s+and\s+(-?\d+))$").unwrap();
+        static ref AS_OF_RE: Regex =
+            Regex::new(r"^(.*?)\s+as\s+of\s+(?:(\d+)|'(.*?)')$").unwrap();
+        static ref TIMESTAMP_RE: Regex =
+            Regex::new(r"^(\d{4})-(\d{2})-(\d{2})(?:[ T](\d{2}):(\d{2}):(\d{2}))?$").unwrap();
+        static ref SAVEPOINT_RE: Regex = Regex::new(
+            r"^(?:savepoint|release(?:\s+savepoint)?|rollback\s+to(?:\s+savepoint)?)\s+(\w+)$"
+        )
+        .unwrap();
     }
 
     pub fn parse_statement(s: &str) -> Option<Statement<i32, Vec<Value>>> {
@@ -29,29 +88,247 @@ pub mod sql {
         match cmd.as_str() {
             "insert" => {
                 let cap = INSERT_RE.captures(s)?;
-                let id = cap.get(1)?.as_str().parse::<i32>().unwrap();
+                let id = cap.get(1)?.as_str().parse::<i32>().ok()?;
                 let vals = [
                     Value::VarChar(VarChar::new(cap.get(2)?.as_str())),
                     Value::VarChar(VarChar::new(cap.get(3)?.as_str())),
                 ];
-                Some(Statement::Insert((id, vals.to_vec())))
+                let on_conflict = match cap.get(4).map(|m| m.as_str()) {
+                    Some("nothing") => OnConflict::DoNothing,
+                    Some(_) => OnConflict::DoUpdate,
+                    None => OnConflict::Error,
+                };
+                Some(Statement::Insert((id, vals.to_vec()), on_conflict))
             }
-            "select" => {
-                let cap = SELECT_RE.captures(s)?;
-                let id_str = cap.get(1)?.as_str();
-                if id_str == "*" {
-                    Some(Statement::SelectAll)
-                } else {
-                    let id = id_str.parse::<i32>().unwrap();
-                    Some(Statement::SelectOne(id))
+            "select" => match AS_OF_RE.captures(s) {
+                Some(cap) => {
+                    let at = match cap.get(2) {
+                        Some(id) => AsOf::Commit(id.as_str().parse::<u64>().ok()?),
+                        None => AsOf::Time(parse_timestamp(cap.get(3)?.as_str())?),
+                    };
+                    let stmt = parse_select(cap.get(1)?.as_str())?;
+                    Some(Statement::AsOf(Box::new(stmt), at))
                 }
-            }
+                None => parse_select(s),
+            },
             "delete" => {
                 let cap = DELETE_RE.captures(s)?;
-                let id = cap.get(1)?.as_str().parse::<i32>().unwrap();
-                Some(Statement::Delete(id))
+                let parse = |i: usize| cap.get(i)?.as_str().parse::<i32>().ok();
+                match parse(1) {
+                    Some(id) => Some(Statement::Delete(id)),
+                    None => Some(Statement::DeleteRange(parse(2)?, parse(3)?)),
+                }
+            }
+            "update" => {
+                let cap = UPDATE_RE.captures(s)?;
+                let key = cap.get(1)?.as_str().parse::<i32>().ok()?;
+                let col = match cap.get(2)?.as_str() {
+                    "username" => 0,
+                    _ => 1,
+                };
+                let val = Value::VarChar(VarChar::new(cap.get(3)?.as_str()));
+                Some(Statement::Update { key, col, val })
+            }
+            "vacuum" | "begin" | "commit" => {
+                if itr.next().is_some() {
+                    return None;
+                }
+                match cmd.as_str() {
+                    "vacuum" => Some(Statement::Vacuum),
+                    "begin" => Some(Statement::Begin),
+                    _ => Some(Statement::Commit),
+                }
+            }
+            "rollback" if itr.next().is_none() => Some(Statement::Rollback),
+            "savepoint" | "release" | "rollback" => {
+                let cap = SAVEPOINT_RE.captures(s)?;
+                let name = cap.get(1)?.as_str().to_string();
+                match cmd.as_str() {
+                    "savepoint" => Some(Statement::Savepoint(name)),
+                    "release" => Some(Statement::Release(name)),
+                    _ => Some(Statement::RollbackTo(name)),
+                }
             }
             _ => None,
         }
     }
+
+    fn parse_select(s: &str) -> Option<Statement<i32, Vec<Value>>> {
+        let cap = SELECT_RE.captures(s)?;
+        match cap.get(1) {
+            Some(id_str) => {
+                let id = id_str.as_str().parse::<i32>().ok()?;
+                Some(Statement::SelectOne(id))
+            }
+            None if cap.get(2).is_some() => Some(Statement::Count),
+            None => {
+                let desc = cap.get(3).is_some_and(|d| d.as_str() == "desc");
+                let limit = match cap.get(4) {
+                    Some(n) => Some(n.as_str().parse::<usize>().ok()?),
+                    None => None,
+                };
+                let offset = match cap.get(5) {
+                    Some(n) => n.as_str().parse::<usize>().ok()?,
+                    None => 0,
+                };
+                Some(Statement::SelectAll {
+                    desc,
+                    limit,
+                    offset,
+                })
+            }
+        }
+    }
+
+    // Parse a UTC time 'YYYY-MM-DD HH:MM:SS', or a date 'YYYY-MM-DD' for
+    // its midnight, into seconds since the Unix epoch.
+    pub fn parse_timestamp(s: &str) -> Option<u64> {
+        let cap = TIMESTAMP_RE.captures(s.trim())?;
+        let num = |i: usize| {
+            cap.get(i)
+                .map_or(Some(0), |m| m.as_str().parse::<i64>().ok())
+        };
+        let (y, m, d) = (num(1)?, num(2)?, num(3)?);
+        let (hh, mm, ss) = (num(4)?, num(5)?, num(6)?);
+        if !(1..=12).contains(&m) || !(1..=31).contains(&d) || hh > 23 || mm > 59 || ss > 59 {
+            return None;
+        }
+        // days past the end of the month roll over into the next one
+        let days = days_from_civil(y, m, d);
+        if civil_from_days(days) != (y, m, d) {
+            return None;
+        }
+        let secs = days * 86400 + hh * 3600 + mm * 60 + ss;
+        u64::try_from(secs).ok()
+    }
+
+    // Format seconds since the Unix epoch as a UTC time 'YYYY-MM-DD HH:MM:SS'.
+    pub fn format_timestamp(secs: u64) -> String {
+        let secs = secs as i64;
+        let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+        let t = secs.rem_euclid(86400);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            y,
+            m,
+            d,
+            t / 3600,
+            t / 60 % 60,
+            t % 60
+        )
+    }
+
+    // Days since 1970-01-01 of a date in the Gregorian calendar, and back.
+    // See http://howardhinnant.github.io/date_algorithms.html
+    fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+        let y = if m <= 2 { y - 1 } else { y };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    fn civil_from_days(days: i64) -> (i64, i64, i64) {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = if mp < 10 { mp + 3 } else { mp - 9 };
+        let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+        (y, m, d)
+    }
+
+    // Parse a CSV line holding a row of the table: id,username,email.
+    // Fields may be wrapped in double quotes, but can't contain commas.
+    pub fn parse_csv_row(line: &str) -> Option<(i32, Vec<Value>)> {
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| {
+                let f = f.trim();
+                f.strip_prefix('"')
+                    .and_then(|f| f.strip_suffix('"'))
+                    .unwrap_or(f)
+            })
+            .collect();
+        if fields.len() != 3 {
+            return None;
+        }
+        let id = fields[0].parse::<i32>().ok()?;
+        let vals = [
+            Value::VarChar(VarChar::new(fields[1])),
+            Value::VarChar(VarChar::new(fields[2])),
+        ];
+        Some((id, vals.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sql::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(parse_timestamp("2024-01-02 03:04:05"), Some(1704164645));
+        assert_eq!(parse_timestamp("2024-01-02T03:04:05"), Some(1704164645));
+        assert_eq!(parse_timestamp(" 2024-02-29 "), Some(1709164800));
+        assert_eq!(parse_timestamp("2000-02-29"), Some(951782400));
+        assert_eq!(parse_timestamp("2024-12-31"), Some(1735603200));
+        assert_eq!(parse_timestamp("1999-12-31 23:59:59"), Some(946684799));
+        // before the epoch, or not a valid time
+        assert_eq!(parse_timestamp("1969-12-31"), None);
+        assert_eq!(parse_timestamp("2024-13-01"), None);
+        assert_eq!(parse_timestamp("2024-01-00"), None);
+        assert_eq!(parse_timestamp("2024-02-31"), None);
+        assert_eq!(parse_timestamp("2023-02-29"), None);
+        assert_eq!(parse_timestamp("2100-02-29"), None);
+        assert_eq!(parse_timestamp("2024-04-31"), None);
+        assert_eq!(parse_timestamp("2024-01-02 24:00:00"), None);
+        assert_eq!(parse_timestamp("2024-01-02 03:04"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(1704164645), "2024-01-02 03:04:05");
+        for secs in [
+            0, 59, 86399, 86400, 946684799, 951782400, 1709164800, 4102444800,
+        ] {
+            assert_eq!(parse_timestamp(&format_timestamp(secs)), Some(secs));
+        }
+    }
+
+    #[test]
+    fn test_parse_as_of() {
+        assert!(matches!(
+            parse_statement("select 5 as of 3"),
+            Some(Statement::AsOf(stmt, AsOf::Commit(3))) if matches!(*stmt, Statement::SelectOne(5))
+        ));
+        assert!(matches!(
+            parse_statement("select * order by id desc limit 2 as of '2024-01-02 03:04:05'"),
+            Some(Statement::AsOf(stmt, AsOf::Time(1704164645)))
+                if matches!(*stmt, Statement::SelectAll { desc: true, limit: Some(2), offset: 0 })
+        ));
+        assert!(matches!(
+            parse_statement("select count(*)  as  of '2024-02-29'"),
+            Some(Statement::AsOf(stmt, AsOf::Time(1709164800))) if matches!(*stmt, Statement::Count)
+        ));
+        // a select without as of is read at the latest commit
+        assert!(matches!(
+            parse_statement("select 5"),
+            Some(Statement::SelectOne(5))
+        ));
+        assert!(parse_statement("select 5 as of").is_none());
+        assert!(parse_statement("select 5 as of -1").is_none());
+        assert!(parse_statement("select 5 as of '2024-13-01'").is_none());
+        assert!(parse_statement("select 5 as of 2024-01-02").is_none());
+        assert!(parse_statement("select as of 3").is_none());
+        // only selects can be read at an earlier commit
+        assert!(parse_statement("delete 5 as of 3").is_none());
+    }
 }
@@ -1,97 +1,393 @@
 pub mod btree {
     use crate::types::values::Serializable;
     use crate::types::values::SerializeError;
-    use std::cmp::Ord;
-    use std::collections::VecDeque;
+    use crate::types::values::VarChar;
+    use std::cell::RefCell;
+    use std::cmp::{Ord, Ordering};
+    use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
     use std::convert::TryFrom;
     use std::fmt;
-    use std::marker::Sized;
-    use std::mem::size_of;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::marker::{PhantomData, Sized};
+    use std::mem::{self, size_of};
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use thiserror::Error;
 
-    /// Key and Val are trait aliases for BTree key-val types.
+    use super::checksum::{crc32, Crc32};
+    use super::wal::Wal;
+
+    /// Key is implemented by BTree key types, and Val is a trait alias for
+    /// BTree val types.
     pub trait Key: Ord + Clone + Serializable + fmt::Debug
     where
         Self: Sized,
     {
+        /// The shortest key s with left <= s < right, given left < right.
+        /// It's used as the separator between two pages instead of left.
+        fn separator(left: &Self, _right: &Self) -> Self {
+            left.clone()
+        }
+
+        /// Bytes that sort in the same order as keys, which the key can be
+        /// decoded from with from_prefix_bytes. Leaves store the prefix the
+        /// keys' bytes have in common once. Keys without them are stored whole.
+        fn prefix_bytes(&self) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn from_prefix_bytes(_bs: &[u8]) -> Result<Self, SerializeError> {
+            Err(SerializeError::InvalidByteLen)
+        }
     }
     pub trait Val: Clone + fmt::Debug + Serializable
     where
         Self: Sized,
     {
     }
-    impl<T> Key for T where T: Ord + Clone + Serializable + fmt::Debug {}
     impl<T> Val for T where T: Clone + fmt::Debug + Serializable {}
 
-    /// ------------------- Error Types -------------------
+    impl Key for i32 {}
 
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    pub struct KeyNotFoundError;
+    impl Key for String {
+        fn separator(left: &Self, right: &Self) -> Self {
+            // the shortest prefix of right that's past left
+            let common = left
+                .bytes()
+                .zip(right.bytes())
+                .take_while(|(l, r)| l == r)
+                .count();
+            let end = (common + 1..=right.len())
+                .find(|&i| right.is_char_boundary(i))
+                .unwrap_or(right.len());
+            if end < right.len() && end < left.len() {
+                right[..end].to_string()
+            } else {
+                left.clone()
+            }
+        }
 
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    pub struct DuplicateKeyError;
+        fn prefix_bytes(&self) -> Option<Vec<u8>> {
+            Some(self.as_bytes().to_vec())
+        }
 
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    pub struct PageNotFoundError;
+        fn from_prefix_bytes(bs: &[u8]) -> Result<Self, SerializeError> {
+            Ok(String::from_utf8(bs.to_vec())?)
+        }
+    }
 
-    impl fmt::Display for KeyNotFoundError {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            write!(f, "key not found")
+    impl Key for VarChar {
+        fn separator(left: &Self, right: &Self) -> Self {
+            let s = String::separator(&left.as_str().to_string(), &right.as_str().to_string());
+            VarChar::new(&s)
+        }
+
+        fn prefix_bytes(&self) -> Option<Vec<u8>> {
+            Some(self.as_str().as_bytes().to_vec())
+        }
+
+        fn from_prefix_bytes(bs: &[u8]) -> Result<Self, SerializeError> {
+            Ok(VarChar::new(&String::from_utf8(bs.to_vec())?))
         }
     }
 
-    impl fmt::Display for DuplicateKeyError {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            write!(f, "duplicate key")
+    // Composite keys are separated by their first field where they differ,
+    // and stored whole.
+    impl<A: Key, B: Key> Key for (A, B) {
+        fn separator(left: &Self, right: &Self) -> Self {
+            if left.0 < right.0 {
+                (A::separator(&left.0, &right.0), left.1.clone())
+            } else {
+                (left.0.clone(), B::separator(&left.1, &right.1))
+            }
         }
     }
 
-    impl fmt::Display for PageNotFoundError {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            write!(f, "page not found")
+    // The separator between the last key of a page and the first of the next.
+    // With duplicate keys the two may be equal.
+    fn separator<K: Key>(left: &K, right: &K) -> K {
+        if left < right {
+            K::separator(left, right)
+        } else {
+            left.clone()
         }
     }
 
-    /// ------------------- BTree Pages -------------------
+    // ------------------- Error Types -------------------
+
+    #[derive(Debug, Error)]
+    pub enum PagerError {
+        #[error("page {0} not found")]
+        PageNotFound(u32),
+        #[error("io error: {0}")]
+        Io(#[from] io::Error),
+        #[error("page {0} is corrupt: checksum mismatch")]
+        Checksum(u32),
+        #[error("page {id} is corrupt: {source}")]
+        Corrupt { id: u32, source: SerializeError },
+        #[error("not a bokedb database file")]
+        BadMagic,
+        #[error(
+            "unsupported database format version {0} (this build reads version {FORMAT_VERSION})"
+        )]
+        UnsupportedVersion(u32),
+        #[error("database page size {0} doesn't match the supported page size {PAGE_SIZE}")]
+        PageSizeMismatch(u32),
+        #[error("database key size {found} doesn't match the expected key size {expected}")]
+        KeySizeMismatch { found: u32, expected: u32 },
+        #[error("database is opened read-only")]
+        ReadOnly,
+        #[error("commit {0} is not retained")]
+        NoCommit(u64),
+        #[error("database is copy-on-write")]
+        CopyOnWrite,
+        #[error("database is not copy-on-write")]
+        NotCopyOnWrite,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum BTreeError {
+        #[error("key not found")]
+        KeyNotFound,
+        #[error("duplicate key")]
+        DuplicateKey,
+        #[error("bulk load input is not sorted")]
+        Unsorted,
+        #[error("tree is not empty")]
+        NotEmpty,
+        #[error("key is larger than {MAX_KEY_SIZE} bytes")]
+        KeyTooLarge,
+        #[error("a transaction is already open")]
+        TransactionOpen,
+        #[error("no transaction is open")]
+        NoTransaction,
+        #[error("no savepoint named `{0}`")]
+        NoSavepoint(String),
+        #[error(transparent)]
+        Pager(#[from] PagerError),
+    }
+
+    // ------------------- BTree Pages -------------------
     #[derive(Debug, Clone, Copy, Eq, PartialEq)]
     pub enum PageType {
         Leaf = 0,
         Interior = 1,
+        Free = 2,     // unused page on the free list
+        Overflow = 3, // part of a val too large to store in a leaf
     }
 
     pub const PAGE_SIZE: usize = 65536;
+    const PAGE_HEADER_LEN: usize = 17;
+    const OVERFLOW_DATA_LEN: usize = PAGE_SIZE - PAGE_HEADER_LEN;
+    // Largest serialized val that's stored in a leaf. Larger vals are spilled
+    // to overflow pages, so that a leaf holds several entries and splits into
+    // two halves that each fit in a page.
+    const MAX_INLINE_VAL: usize = PAGE_SIZE / 8;
+    // Largest serialized key that can be stored. Keys aren't spilled, so this
+    // is kept small enough for interior pages to hold many of them.
+    pub const MAX_KEY_SIZE: usize = PAGE_SIZE / 16;
+    // A page other than the root is underfull below this fill, see BTree::fill.
+    const MIN_FILL: f64 = 0.5;
 
     /// Page is a BTree page, which can hold keys or key-vals
     #[derive(Debug, Clone)]
     pub struct Page<K: Key, V: Val> {
         id: u32,
         ptype: PageType,
-        deleted: Vec<bool>,   // soft delete info for leaf pages
-        keys: Vec<K>,         // keys for interior and leaf pages
-        vals: Vec<V>,         // vals corresponding to keys for leaf pages
-        children: Vec<u32>,   // child page IDs for interior pages
-        sibling: Option<u32>, // right sibling page ID for leaf pages
+        versions: Vec<Version>,     // versions of the entries for leaf pages
+        keys: Vec<K>,               // keys for interior and leaf pages
+        vals: Vec<V>,               // vals corresponding to keys for leaf pages
+        overflow: Vec<Option<u32>>, // first overflow page of spilled vals for leaf pages
+        children: Vec<u32>,         // child page IDs for interior pages
+        counts: Vec<usize>,         // live entries under each child for interior pages
+        sibling: Option<u32>, // right sibling page ID for leaf pages, next free page for free pages
+        left_sibling: Option<u32>, // left sibling page ID for leaf pages
+    }
+
+    /// Version holds the ids of the transactions that created and deleted
+    /// a leaf entry, so snapshots can tell which entries they see. Changes
+    /// made while no snapshot could tell them apart are stamped with id 0,
+    /// which every snapshot sees.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Version {
+        pub created: u64,
+        pub deleted: Option<u64>,
+    }
+
+    impl Version {
+        fn new(created: u64) -> Self {
+            Version {
+                created,
+                deleted: None,
+            }
+        }
+
+        // Whether the entry is live in the latest state of the tree.
+        fn is_live(&self) -> bool {
+            self.deleted.is_none()
+        }
+    }
+
+    // ------------------- Database Header -------------------
+
+    pub const MAGIC: [u8; 8] = *b"bokedb\0\0";
+    pub const FORMAT_VERSION: u32 = 10;
+    const HEADER_LEN: usize = 73;
+
+    /// Header holds the BTree metadata. It is stored in page 0, so BTree
+    /// pages are numbered from 1.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Header {
+        pub page_size: u32,
+        pub key_size: u32,
+        pub b: u32,
+        pub is_unique: bool,
+        pub depth: u32,
+        pub root_id: u32,
+        pub next_id: u32,
+        pub free_head: Option<u32>, // first page of the free list
+        pub free_count: u32,
+        pub next_txn: u64, // id stamped on entries written outside transactions
+        pub commit: u64,   // last commit of a copy-on-write tree, 0 for other trees
+        pub meta_head: Option<u32>, // first page of a copy-on-write tree's metadata
+        pub meta_len: u32,
+    }
+
+    impl Header {
+        /// The byte layout of the header is as follows:
+        ///
+        ///  0-8     8-12      12-16       16-20      20-24   24
+        /// +-------+---------+-----------+----------+-------+-----------+
+        /// | magic | version | page size | key size | b     | is unique |
+        /// +-------+---------+-----------+----------+-------+-----------+
+        /// | depth | root id | next id   | free head | free count | next txn |
+        /// +-------+---------+-----------+-----------+------------+----------+
+        ///  25-29   29-33     33-37       37-41       41-45        45-53
+        /// | commit | meta head | meta len | checksum |
+        /// +--------+-----------+----------+----------+
+        ///  53-61    61-65       65-69      69-73
+        ///
+        /// The meta head and len locate the list of retained commits and free
+        /// pages of a copy-on-write tree, see BTree::open_shadow. The checksum
+        /// is the CRC-32 of the preceding bytes.
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes = Vec::with_capacity(HEADER_LEN);
+            bytes.extend(MAGIC);
+            bytes.extend(FORMAT_VERSION.to_le_bytes());
+            bytes.extend(self.page_size.to_le_bytes());
+            bytes.extend(self.key_size.to_le_bytes());
+            bytes.extend(self.b.to_le_bytes());
+            bytes.push(self.is_unique as u8);
+            bytes.extend(self.depth.to_le_bytes());
+            bytes.extend(self.root_id.to_le_bytes());
+            bytes.extend(self.next_id.to_le_bytes());
+            bytes.extend(self.free_head.unwrap_or(u32::MAX).to_le_bytes());
+            bytes.extend(self.free_count.to_le_bytes());
+            bytes.extend(self.next_txn.to_le_bytes());
+            bytes.extend(self.commit.to_le_bytes());
+            bytes.extend(self.meta_head.unwrap_or(u32::MAX).to_le_bytes());
+            bytes.extend(self.meta_len.to_le_bytes());
+            bytes.extend(crc32(&bytes).to_le_bytes());
+            bytes
+        }
+
+        // Decode and validate a header written by to_bytes.
+        pub fn from_bytes(bs: &[u8]) -> Result<Header, PagerError> {
+            if bs.len() < HEADER_LEN || bs[0..8] != MAGIC {
+                return Err(PagerError::BadMagic);
+            }
+            let u32_at = |i: usize| u32::from_le_bytes(bs[i..(i + 4)].try_into().unwrap());
+            let version = u32_at(8);
+            if version != FORMAT_VERSION {
+                return Err(PagerError::UnsupportedVersion(version));
+            }
+            if crc32(&bs[0..69]) != u32_at(69) {
+                return Err(PagerError::Checksum(0));
+            }
+            let page_size = u32_at(12);
+            if page_size as usize != PAGE_SIZE {
+                return Err(PagerError::PageSizeMismatch(page_size));
+            }
+            Ok(Header {
+                page_size,
+                key_size: u32_at(16),
+                b: u32_at(20),
+                is_unique: bs[24] != 0,
+                depth: u32_at(25),
+                root_id: u32_at(29),
+                next_id: u32_at(33),
+                free_head: Some(u32_at(37)).filter(|&id| id != u32::MAX),
+                free_count: u32_at(41),
+                next_txn: u64::from_le_bytes(bs[45..53].try_into().unwrap()),
+                commit: u64::from_le_bytes(bs[53..61].try_into().unwrap()),
+                meta_head: Some(u32_at(61)).filter(|&id| id != u32::MAX),
+                meta_len: u32_at(65),
+            })
+        }
     }
 
     pub trait Pager<K: Key, V: Val>: fmt::Debug {
-        fn read_page(&self, id: u32) -> Result<&Page<K, V>, PageNotFoundError>;
-        fn write_page(&mut self, page: &Page<K, V>);
+        fn read_page(&self, id: u32) -> Result<Page<K, V>, PagerError>;
+        fn write_page(&mut self, page: &Page<K, V>) -> Result<(), PagerError>;
+        // Returns None if the store is empty.
+        fn read_header(&self) -> Result<Option<Header>, PagerError>;
+        fn write_header(&mut self, header: &Header) -> Result<(), PagerError>;
+        // Make previous writes durable.
+        fn sync(&mut self) -> Result<(), PagerError>;
+        // Move durable writes into their final location, if the pager
+        // stages them elsewhere first.
+        fn checkpoint(&mut self) -> Result<(), PagerError>;
+        // Overflow pages hold vals too large to store in a leaf, in chains
+        // linked by the next page ID. They don't go through the buffer pool.
+        fn read_overflow(&self, id: u32) -> Result<(Option<u32>, Vec<u8>), PagerError>;
+        fn write_overflow(
+            &mut self,
+            id: u32,
+            next: Option<u32>,
+            data: &[u8],
+        ) -> Result<(), PagerError>;
     }
 
-    // MemPager is a simple in-memory page store.
+    // MemPager is a simple in-memory page store, backing the trees of tests.
+    #[cfg(test)]
     #[derive(Debug)]
     pub struct MemPager<K: Key, V: Val> {
+        header: Option<Header>,
         pages: Vec<Page<K, V>>,
+        overflow: HashMap<u32, (Option<u32>, Vec<u8>)>,
+    }
+
+    #[cfg(test)]
+    impl<K: Key, V: Val> MemPager<K, V> {
+        pub fn new() -> Self {
+            MemPager {
+                header: None,
+                pages: vec![],
+                overflow: HashMap::new(),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    impl<K: Key, V: Val> Default for MemPager<K, V> {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
+    #[cfg(test)]
     impl<K: Key, V: Val> Pager<K, V> for MemPager<K, V> {
-        fn read_page(&self, id: u32) -> Result<&Page<K, V>, PageNotFoundError> {
+        fn read_page(&self, id: u32) -> Result<Page<K, V>, PagerError> {
             let res = self.pages.binary_search_by_key(&id, |p| p.id);
             match res {
-                Ok(idx) => Ok(&self.pages[idx]),
-                Err(_) => Err(PageNotFoundError),
+                Ok(idx) => Ok(self.pages[idx].clone()),
+                Err(_) => Err(PagerError::PageNotFound(id)),
             }
         }
-        fn write_page(&mut self, page: &Page<K, V>) {
+        fn write_page(&mut self, page: &Page<K, V>) -> Result<(), PagerError> {
+            self.overflow.remove(&page.id);
             let res = self.pages.binary_search_by_key(&page.id, |p| p.id);
             match res {
                 Ok(idx) => {
@@ -101,833 +397,6040 @@ pub mod btree {
                     self.pages.insert(idx, page.clone());
                 }
             }
+            Ok(())
+        }
+        fn read_header(&self) -> Result<Option<Header>, PagerError> {
+            Ok(self.header.clone())
+        }
+        fn write_header(&mut self, header: &Header) -> Result<(), PagerError> {
+            self.header = Some(header.clone());
+            Ok(())
+        }
+        fn sync(&mut self) -> Result<(), PagerError> {
+            Ok(())
+        }
+        fn checkpoint(&mut self) -> Result<(), PagerError> {
+            Ok(())
+        }
+        fn read_overflow(&self, id: u32) -> Result<(Option<u32>, Vec<u8>), PagerError> {
+            self.overflow
+                .get(&id)
+                .cloned()
+                .ok_or(PagerError::PageNotFound(id))
+        }
+        fn write_overflow(
+            &mut self,
+            id: u32,
+            next: Option<u32>,
+            data: &[u8],
+        ) -> Result<(), PagerError> {
+            self.overflow.insert(id, (next, data.to_vec()));
+            Ok(())
         }
     }
 
-    pub fn pack_bits(bits: &Vec<bool>) -> Vec<u8> {
-        let len = (bits.len() + 7) / 8;
-        let mut bs = vec![0x0; len];
-        // write bits in LE order
-        for i in 0..len {
-            for j in 0..8 {
-                if 8 * i + j >= bits.len() {
-                    break;
-                }
-                bs[i] ^= (bits[8 * i + j] as u8) << (7 - j);
+    // FilePager stores pages in a single database file.
+    // The page with a given id is stored at byte offset id * PAGE_SIZE.
+    // Writes go to a write-ahead log next to the database file and are copied
+    // into the database file by checkpoints.
+    #[derive(Debug)]
+    pub struct FilePager<K: Key, V: Val> {
+        file: File,
+        wal: Wal,
+        n_pages: u32,
+        _marker: PhantomData<(K, V)>,
+    }
+
+    // Checkpoint automatically once the log holds this many pages.
+    pub const WAL_AUTOCHECKPOINT: usize = 1000;
+
+    impl<K: Key, V: Val> FilePager<K, V> {
+        // Open the database file at path, creating it if it doesn't exist.
+        // Pages committed to the log before a crash are recovered into the file.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PagerError> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            let len = file.metadata()?.len();
+            let n_pages = u32::try_from(len.div_ceil(PAGE_SIZE as u64)).unwrap();
+
+            let mut wal_path = path.as_ref().as_os_str().to_owned();
+            wal_path.push("-wal");
+            let wal = Wal::open(wal_path)?;
+
+            let mut pager = FilePager {
+                file,
+                wal,
+                n_pages,
+                _marker: PhantomData,
+            };
+            pager.checkpoint_wal()?;
+            Ok(pager)
+        }
+
+        fn offset(id: u32) -> u64 {
+            id as u64 * PAGE_SIZE as u64
+        }
+
+        // Read the latest image of the page, from the log if it's there.
+        fn read_raw(&self, id: u32, len: usize) -> Result<Vec<u8>, PagerError> {
+            if let Some(bytes) = self.wal.read(id)? {
+                return Ok(bytes);
             }
+            let mut buf = Vec::with_capacity(len);
+            let mut file = &self.file;
+            file.seek(SeekFrom::Start(Self::offset(id)))?;
+            file.take(len as u64).read_to_end(&mut buf)?;
+            Ok(buf)
         }
-        bs
-    }
 
-    // inverse of pack_bits
-    pub fn unpack_bits(len: usize, bytes: &[u8]) -> Vec<bool> {
-        let mut bits: Vec<bool> = vec![];
-        for b in bytes.iter() {
-            for j in (0..8).rev() {
-                let bit = ((*b >> j) & 1) == 1;
-                bits.push(bit);
+        // Copy committed pages from the log into the database file and
+        // empty the log. Does nothing while uncommitted pages are logged.
+        fn checkpoint_wal(&mut self) -> Result<(), PagerError> {
+            let ids = self.wal.committed_pages();
+            if ids.is_empty() || self.wal.has_pending() {
+                return Ok(());
             }
+            for id in ids {
+                let bytes = self.wal.read(id)?.unwrap();
+                self.file.seek(SeekFrom::Start(Self::offset(id)))?;
+                self.file.write_all(&bytes)?;
+                self.n_pages = self.n_pages.max(id + 1);
+            }
+            self.file.sync_data()?;
+            self.wal.reset()
         }
-        bits.truncate(len);
-        bits
     }
 
-    impl<K: Key, V: Val> Page<K, V> {
-        // Return the first index <= key
-        fn find(&self, key: &K) -> usize {
-            let mut i = 0;
-            for k in self.keys.iter() {
-                if key > k {
-                    i += 1;
-                } else {
-                    break;
-                }
+    impl<K: Key, V: Val> Pager<K, V> for FilePager<K, V> {
+        fn read_page(&self, id: u32) -> Result<Page<K, V>, PagerError> {
+            if id >= self.n_pages {
+                return Err(PagerError::PageNotFound(id));
             }
-            i
+            let buf = self.read_raw(id, PAGE_SIZE)?;
+            Page::from_stored_bytes(id, &buf, &|head, len| read_chain(self, head, len))
         }
+        fn write_page(&mut self, page: &Page<K, V>) -> Result<(), PagerError> {
+            self.wal.append(page.id, &page.to_bytes())?;
+            self.n_pages = self.n_pages.max(page.id + 1);
+            Ok(())
+        }
+        fn read_header(&self) -> Result<Option<Header>, PagerError> {
+            if self.n_pages == 0 {
+                return Ok(None);
+            }
+            let buf = self.read_raw(0, HEADER_LEN)?;
+            Ok(Some(Header::from_bytes(&buf)?))
+        }
+        fn write_header(&mut self, header: &Header) -> Result<(), PagerError> {
+            let mut bytes = header.to_bytes();
+            bytes.resize(PAGE_SIZE, 0);
+            self.wal.append(0, &bytes)?;
+            self.n_pages = self.n_pages.max(1);
+            Ok(())
+        }
+        // Commit the logged pages.
+        fn sync(&mut self) -> Result<(), PagerError> {
+            self.wal.commit()?;
+            if self.wal.frames() >= WAL_AUTOCHECKPOINT {
+                self.checkpoint_wal()?;
+            }
+            Ok(())
+        }
+        fn checkpoint(&mut self) -> Result<(), PagerError> {
+            self.wal.commit()?;
+            self.checkpoint_wal()
+        }
+        fn read_overflow(&self, id: u32) -> Result<(Option<u32>, Vec<u8>), PagerError> {
+            if id >= self.n_pages {
+                return Err(PagerError::PageNotFound(id));
+            }
+            overflow_from_bytes(id, &self.read_raw(id, PAGE_SIZE)?)
+        }
+        fn write_overflow(
+            &mut self,
+            id: u32,
+            next: Option<u32>,
+            data: &[u8],
+        ) -> Result<(), PagerError> {
+            self.wal.append(id, &overflow_to_bytes(id, next, data))?;
+            self.n_pages = self.n_pages.max(id + 1);
+            Ok(())
+        }
+    }
 
-        /// The byte layout of a page is as follows:
-        ///
-        /// Interior Page
-        ///  0-4    4          5-9       9-13
-        /// +----+-----------+----------+---------+
-        /// | id | page type | key size | key len |
-        /// +----+-----------+----------+---------+
-        /// | keys           | children           |
-        /// +----------------+--------------------+
-
-        /// Leaf Page
-        ///  0-4    4          5-9       9-13
-        /// +----+-----------+----------+---------+
-        /// | id | page type | key size | key len |
-        /// +----+-----------+----------+---------+
-        /// | keys | sibling | deleted  | vals    |
-        /// +----------------+--------------------+
-        fn to_bytes(&self) -> [u8; PAGE_SIZE] {
-            let mut bytes = [0; PAGE_SIZE];
-            let id_bytes: [u8; 4] = self.id.to_le_bytes();
-            bytes[0..4].copy_from_slice(&id_bytes);
-            bytes[4] = self.ptype as u8;
-            let key_size = u32::try_from(size_of::<K>()).unwrap();
-            bytes[5..9].copy_from_slice(&key_size.to_le_bytes());
-            let keys_len = u32::try_from(self.keys.len()).unwrap();
-            bytes[9..13].copy_from_slice(&keys_len.to_le_bytes());
+    // Read len bytes of a val from the chain of overflow pages at head.
+    fn read_chain<K: Key, V: Val>(
+        pager: &dyn Pager<K, V>,
+        head: u32,
+        len: usize,
+    ) -> Result<Vec<u8>, PagerError> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut next = Some(head);
+        while bytes.len() < len {
+            let Some(id) = next else {
+                return Err(PagerError::Corrupt {
+                    id: head,
+                    source: SerializeError::InvalidByteLen,
+                });
+            };
+            let (n, data) = pager.read_overflow(id)?;
+            bytes.extend(data);
+            next = n;
+        }
+        bytes.truncate(len);
+        Ok(bytes)
+    }
 
-            let key_usize = key_size as usize;
-            let mut i = 13;
-            for k in self.keys.iter() {
-                bytes[i..(i + key_usize)].copy_from_slice(&k.to_bytes());
-                i += key_usize;
-            }
+    /// ShadowPager is a Pager on a copy-on-write database file, written by
+    /// trees opened with BTree::open_shadow. Like FilePager it stores the
+    /// page with a given id at byte offset id * PAGE_SIZE, but writes pages
+    /// in place without a log, since such a tree never writes to the pages
+    /// its last commit uses. Page 0 holds two copies of the header, at
+    /// offsets 0 and PAGE_SIZE / 2, which odd and even commits write in turn
+    /// once the commit's pages are synced. A torn header write leaves the
+    /// other copy, and the commit before, intact. The copy with the latest
+    /// commit is read.
+    #[derive(Debug)]
+    pub struct ShadowPager<K: Key, V: Val> {
+        file: File,
+        read_only: bool,
+        n_pages: u32,
+        _marker: PhantomData<(K, V)>,
+    }
 
-            match self.ptype {
-                PageType::Interior => {
-                    for c in self.children.iter() {
-                        bytes[i..(i + 4)].copy_from_slice(&c.to_le_bytes());
-                        i += 4;
-                    }
-                }
-                PageType::Leaf => {
-                    assert_eq!(self.deleted.len(), self.vals.len());
-                    assert_eq!(self.vals.len(), self.keys.len());
-                    let sib = self.sibling.unwrap_or(u32::MAX);
-                    bytes[i..(i + 4)].copy_from_slice(&sib.to_le_bytes());
-                    i += 4;
+    const HEADER_OFFSETS: [u64; 2] = [0, PAGE_SIZE as u64 / 2];
 
-                    let del_bytes = pack_bits(&self.deleted);
-                    let del_len = del_bytes.len();
-                    bytes[i..(i + del_len)].copy_from_slice(&del_bytes);
-                    i += del_len;
+    impl<K: Key, V: Val> ShadowPager<K, V> {
+        // Open the database file at path, creating it if it doesn't exist.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PagerError> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            Self::from_file(file, false)
+        }
 
-                    for v in self.vals.iter() {
-                        let v_bytes = v.to_bytes();
-                        let v_len = v_bytes.len();
-                        bytes[i..(i + v_len)].copy_from_slice(&v_bytes);
-                        i += v_len;
-                    }
-                }
-            }
-            bytes
+        // Open the database file at path for reading only. Writes fail with
+        // PagerError::ReadOnly.
+        pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, PagerError> {
+            Self::from_file(File::open(path)?, true)
         }
 
-        fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
-            let id_bytes: [u8; 4] = bs[0..4].try_into().unwrap();
-            let id = u32::from_le_bytes(id_bytes);
+        fn from_file(file: File, read_only: bool) -> Result<Self, PagerError> {
+            let len = file.metadata()?.len();
+            Ok(ShadowPager {
+                file,
+                read_only,
+                n_pages: u32::try_from(len.div_ceil(PAGE_SIZE as u64)).unwrap(),
+                _marker: PhantomData,
+            })
+        }
 
-            let ptype = if bs[4] == PageType::Interior as u8 {
-                PageType::Interior
-            } else {
-                PageType::Leaf
-            };
+        fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>, PagerError> {
+            let mut buf = Vec::with_capacity(len);
+            let mut file = &self.file;
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(len as u64).read_to_end(&mut buf)?;
+            Ok(buf)
+        }
 
-            let key_size_bytes: [u8; 4] = bs[5..9].try_into().unwrap();
-            let key_size = u32::from_le_bytes(key_size_bytes);
-            let keys_len_bytes: [u8; 4] = bs[9..13].try_into().unwrap();
-            let keys_len = u32::from_le_bytes(keys_len_bytes);
+        fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), PagerError> {
+            if self.read_only {
+                return Err(PagerError::ReadOnly);
+            }
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(bytes)?;
+            let end = (offset + bytes.len() as u64).div_ceil(PAGE_SIZE as u64);
+            self.n_pages = self.n_pages.max(u32::try_from(end).unwrap());
+            Ok(())
+        }
 
-            let key_usize = key_size as usize;
-            let keys_len_usize = keys_len as usize;
-            let mut keys = Vec::with_capacity(keys_len_usize);
-            let mut i = 13;
-            for j in 0..keys_len_usize {
-                let (size, key) = K::from_bytes(&bs[i..(i + key_usize)])?;
-                keys[j] = key;
-                i += size;
+        fn read_raw(&self, id: u32) -> Result<Vec<u8>, PagerError> {
+            if id >= self.n_pages {
+                return Err(PagerError::PageNotFound(id));
             }
+            self.read_at(FilePager::<K, V>::offset(id), PAGE_SIZE)
+        }
+    }
 
-            match ptype {
-                PageType::Interior => {
-                    let mut children = Vec::with_capacity(keys_len_usize);
-                    for j in 0..keys_len_usize {
-                        let cbytes: [u8; 4] = bs[i..(i + 4)].try_into().unwrap();
-                        let c = u32::from_le_bytes(cbytes);
-                        children[j] = c;
-                        i += 4;
+    impl<K: Key, V: Val> Pager<K, V> for ShadowPager<K, V> {
+        fn read_page(&self, id: u32) -> Result<Page<K, V>, PagerError> {
+            let buf = self.read_raw(id)?;
+            Page::from_stored_bytes(id, &buf, &|head, len| read_chain(self, head, len))
+        }
+        fn write_page(&mut self, page: &Page<K, V>) -> Result<(), PagerError> {
+            self.write_at(FilePager::<K, V>::offset(page.id), &page.to_bytes())
+        }
+        // Read the copy of the header with the latest commit. If neither copy
+        // is valid, the error is the first copy's.
+        fn read_header(&self) -> Result<Option<Header>, PagerError> {
+            if self.n_pages == 0 {
+                return Ok(None);
+            }
+            let mut latest: Option<Header> = None;
+            let mut err = None;
+            for offset in HEADER_OFFSETS {
+                match Header::from_bytes(&self.read_at(offset, HEADER_LEN)?) {
+                    Ok(header) if latest.as_ref().is_none_or(|l| l.commit < header.commit) => {
+                        latest = Some(header)
                     }
-
-                    Ok((
-                        i,
-                        Page {
-                            id,
-                            ptype,
-                            keys,
-                            children,
-                            vals: vec![],
-                            deleted: vec![],
-                            sibling: None,
-                        },
-                    ))
-                }
-                PageType::Leaf => {
-                    let sib_bytes: [u8; 4] = bs[i..(i + 4)].try_into().unwrap();
-                    let sib_id = u32::from_le_bytes(sib_bytes);
-                    let sibling = if sib_id == u32::MAX {
-                        None
-                    } else {
-                        Some(sib_id)
-                    };
-
-                    let del_len = ((keys_len_usize + 7) / 8);
-                    let deleted = unpack_bits(keys_len_usize, &bs[i..(i + del_len)]);
-                    i += del_len;
-
-                    let mut vals = Vec::with_capacity(keys_len_usize);
-                    for j in 0..keys_len_usize {
-                        let (size, val) = V::from_bytes(&bs[i..])?;
-                        vals[j] = val;
-                        i += size;
+                    Ok(_) => {}
+                    Err(e) => {
+                        err.get_or_insert(e);
                     }
-
-                    Ok((
-                        i,
-                        Page {
-                            id,
-                            ptype,
-                            keys,
-                            deleted,
-                            sibling,
-                            vals,
-                            children: vec![],
-                        },
-                    ))
                 }
             }
+            match latest {
+                Some(header) => Ok(Some(header)),
+                None => Err(err.unwrap()),
+            }
+        }
+        // The pages the header points to are synced before it's written.
+        fn write_header(&mut self, header: &Header) -> Result<(), PagerError> {
+            if self.read_only {
+                return Err(PagerError::ReadOnly);
+            }
+            self.file.sync_data()?;
+            let offset = HEADER_OFFSETS[header.commit.is_multiple_of(2) as usize];
+            self.write_at(offset, &header.to_bytes())
+        }
+        fn sync(&mut self) -> Result<(), PagerError> {
+            self.file.sync_data()?;
+            Ok(())
+        }
+        // Pages are written in place, so there's nothing to move.
+        fn checkpoint(&mut self) -> Result<(), PagerError> {
+            Ok(())
+        }
+        fn read_overflow(&self, id: u32) -> Result<(Option<u32>, Vec<u8>), PagerError> {
+            overflow_from_bytes(id, &self.read_raw(id)?)
+        }
+        fn write_overflow(
+            &mut self,
+            id: u32,
+            next: Option<u32>,
+            data: &[u8],
+        ) -> Result<(), PagerError> {
+            let offset = FilePager::<K, V>::offset(id);
+            self.write_at(offset, &overflow_to_bytes(id, next, data))
         }
     }
 
-    /// ------------------- BTree Implementation -------------------
+    // ------------------- Buffer Pool -------------------
+
+    pub const DEFAULT_POOL_CAPACITY: usize = 1024;
+
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct PoolStats {
+        pub hits: u64,
+        pub misses: u64,
+        pub evictions: u64,
+        pub writes: u64, // pages written back to the pager
+    }
 
-    /// BTree implements a B+Tree.
-    /// Each page has at most b children, where b is odd.
     #[derive(Debug)]
-    pub struct BTree<K: Key, V: Val> {
-        b: usize,
-        is_unique: bool,
-        depth: usize,
-        root_id: u32,
-        next_id: u32,
-        pager: Box<dyn Pager<K, V>>,
+    struct Frame<K: Key, V: Val> {
+        page: Option<Rc<Page<K, V>>>, // None while the page is checked out by take
+        dirty: bool,
+        tick: u64,
     }
 
-    impl<K: Key + 'static, V: Val + 'static> fmt::Display for BTree<K, V> {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let vecs = self.traverse();
-            for (l, ids) in vecs.iter().enumerate() {
-                f.write_fmt(format_args!("LEVEL {:?}\n", l))?;
+    #[derive(Debug)]
+    struct PoolState<K: Key, V: Val> {
+        frames: HashMap<u32, Frame<K, V>>,
+        lru: BTreeMap<u64, u32>, // last use tick -> page ID, for pages not checked out
+        tick: u64,
+        stats: PoolStats,
+    }
 
-                for &id in ids.iter() {
-                    f.write_fmt(format_args!("\t{:?}\n", self.pager.read_page(id).unwrap()))?;
+    impl<K: Key, V: Val> PoolState<K, V> {
+        fn touch(&mut self, id: u32) -> Option<Rc<Page<K, V>>> {
+            let frame = self.frames.get_mut(&id)?;
+            let page = match &frame.page {
+                Some(page) => page.clone(),
+                None => panic!("page {} is checked out", id),
+            };
+            self.lru.remove(&frame.tick);
+            self.tick += 1;
+            frame.tick = self.tick;
+            self.lru.insert(self.tick, id);
+            Some(page)
+        }
+
+        // Evict least recently used pages until at most max_len are cached,
+        // writing back the dirty ones.
+        fn evict(&mut self, pager: &mut dyn Pager<K, V>, max_len: usize) -> Result<(), PagerError> {
+            while self.frames.len() > max_len {
+                let (_, id) = match self.lru.pop_first() {
+                    Some(entry) => entry,
+                    None => break, // everything is checked out
+                };
+                let frame = self.frames.remove(&id).unwrap();
+                if frame.dirty {
+                    pager.write_page(frame.page.as_ref().unwrap())?;
+                    self.stats.writes += 1;
                 }
+                self.stats.evictions += 1;
             }
             Ok(())
         }
-    }
 
-    impl<K: Key + 'static, V: Val + 'static> BTree<K, V> {
-        pub fn new(b: usize, is_unique: bool) -> BTree<K, V> {
-            assert_eq!(b % 2, 1);
-            assert!(b > 2);
+        // Cache page, evicting pages to stay within capacity.
+        fn admit(
+            &mut self,
+            pager: &mut dyn Pager<K, V>,
+            capacity: usize,
+            page: Rc<Page<K, V>>,
+            dirty: bool,
+        ) -> Result<(), PagerError> {
+            self.evict(pager, capacity - 1)?;
+            self.tick += 1;
+            self.lru.insert(self.tick, page.id);
+            self.frames.insert(
+                page.id,
+                Frame {
+                    page: Some(page),
+                    dirty,
+                    tick: self.tick,
+                },
+            );
+            Ok(())
+        }
+    }
 
-            let pager = Box::new(MemPager {
-                pages: vec![Page {
-                    id: 0,
-                    keys: vec![],
-                    vals: vec![],
-                    children: vec![],
-                    deleted: vec![],
-                    ptype: PageType::Leaf,
-                    sibling: None,
-                }],
-            });
+    // Images of pages from before they were first changed in a transaction,
+    // or None for pages that didn't exist yet.
+    type UndoLog<K, V> = HashMap<u32, Option<Rc<Page<K, V>>>>;
 
-            BTree {
-                b,
-                is_unique,
-                pager,
-                depth: 0,
-                root_id: 0,
-                next_id: 1,
-            }
-        }
-
-        // Rebuild the tree to remove soft deleted keys.
-        // pub fn rebuild(&mut self) {
-        //     let mut id = self.root_id;
-        //     for _ in 0..self.depth {
-        //         let page = &self.pager.read_page(id).unwrap();
-        //         id = page.children[0];
-        //     }
-
-        //     // traverse leaf pages to collect kv's
-        //     let mut keys: Vec<K> = Vec::new();
-        //     let mut vals: Vec<V> = Vec::new();
-        //     let max_pages = self.next_id;
-        //     for _ in 0..max_pages {
-        //         let page = self.pager.read_page(id).unwrap().clone();
-
-        //         // copy keys and vals that aren't marked deleted
-        //         let mut del = page.deleted.iter();
-        //         let mut keep_keys: Vec<K> = page.keys.drain(0..).collect();
-        //         keep_keys.retain(|_| !*del.next().unwrap());
-        //         keys.extend(keep_keys.into_iter());
-
-        //         del = page.deleted.iter();
-        //         let mut keep_vals: Vec<V> = page.vals.drain(0..).collect();
-        //         keep_vals.retain(|_| !*del.next().unwrap());
-        //         vals.extend(keep_vals.into_iter());
-
-        //         match page.sibling {
-        //             Some(sid) => {
-        //                 id = sid;
-        //             }
-        //             None => {
-        //                 break;
-        //             }
-        //         }
-        //     }
-
-        //     // reset tree
-        //     self.pages = Self::new(self.b, self.is_unique).pages;
-        //     self.depth = 0;
-        //     self.root_id = 0;
-        //     self.next_id = 1;
-        //     for (k, v) in keys.into_iter().zip(vals.into_iter()) {
-        //         let _ = self.insert(k, v);
-        //     }
-        // }
+    /// BufferPool caches a bounded number of decoded pages in front of a Pager.
+    /// Modified pages are only written back when evicted or flushed.
+    /// While a transaction is open, the pool keeps an undo log of the pages
+    /// it changes, so they can be rolled back. Each savepoint starts a new
+    /// log on top of the last.
+    #[derive(Debug)]
+    pub struct BufferPool<K: Key, V: Val> {
+        pager: RefCell<Box<dyn Pager<K, V>>>,
+        capacity: usize,
+        state: RefCell<PoolState<K, V>>,
+        undo: Vec<UndoLog<K, V>>,
+    }
 
-        // Return the value associated with key, or None if it doesn't exist.
-        // If there are multiple values associated with the key, any can be returned.
-        pub fn find(&self, key: &K) -> Option<V> {
-            let id = self.find_leaf(key);
-            let leaf = self.pager.read_page(id).unwrap();
-            match leaf.keys.binary_search(key) {
-                Ok(idx) => {
-                    if leaf.deleted[idx] {
-                        None
-                    } else {
-                        Some(leaf.vals[idx].clone())
-                    }
-                }
-                Err(_) => None,
+    impl<K: Key, V: Val> BufferPool<K, V> {
+        pub fn new(pager: Box<dyn Pager<K, V>>, capacity: usize) -> Self {
+            assert!(capacity > 0);
+            BufferPool {
+                pager: RefCell::new(pager),
+                capacity,
+                state: RefCell::new(PoolState {
+                    frames: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    tick: 0,
+                    stats: PoolStats::default(),
+                }),
+                undo: vec![],
             }
         }
 
-        // Find key-value pairs where the min <= key <= max.
-        pub fn find_range(&self, min: &K, max: &K) -> Vec<(K, V)> {
-            let mut kvs = vec![];
-            let mut id = self.find_leaf(min);
-            let mut leaf = self.pager.read_page(id).unwrap();
-            let mut idx = match leaf.keys.binary_search(min) {
-                Ok(i) => i,
-                Err(i) => i,
+        // Get a shared reference to the page, reading it from the pager on a miss.
+        pub fn fetch(&self, id: u32) -> Result<Rc<Page<K, V>>, PagerError> {
+            let mut state = self.state.borrow_mut();
+            if let Some(page) = state.touch(id) {
+                state.stats.hits += 1;
+                return Ok(page);
+            }
+            state.stats.misses += 1;
+            let mut pager = self.pager.borrow_mut();
+            let page = Rc::new(pager.read_page(id)?);
+            state.admit(pager.as_mut(), self.capacity, page.clone(), false)?;
+            Ok(page)
+        }
+
+        // Check out the page for modification. The page stays pinned in the pool
+        // until it is handed back with put (modified) or release (unmodified).
+        pub fn take(&mut self, id: u32) -> Result<Page<K, V>, PagerError> {
+            let state = self.state.get_mut();
+            if state.touch(id).is_none() {
+                state.stats.misses += 1;
+                let pager = self.pager.get_mut();
+                let page = Rc::new(pager.read_page(id)?);
+                state.admit(pager.as_mut(), self.capacity, page, false)?;
+            } else {
+                state.stats.hits += 1;
+            }
+            let frame = state.frames.get_mut(&id).unwrap();
+            state.lru.remove(&frame.tick);
+            let page = frame.page.take().unwrap();
+            if let Some(undo) = self.undo.last_mut() {
+                undo.entry(id).or_insert_with(|| Some(page.clone()));
+            }
+            // the pool's reference was dropped, so this only clones if a
+            // reader still holds the page or it's in the undo log
+            Ok(Rc::unwrap_or_clone(page))
+        }
+
+        // Store a new or modified page. It's marked dirty until flushed.
+        pub fn put(&mut self, page: Page<K, V>) -> Result<(), PagerError> {
+            self.log_undo(page.id);
+            self.check_in(page, true)
+        }
+
+        // Hand back a page checked out with take without modifying it.
+        pub fn release(&mut self, page: Page<K, V>) -> Result<(), PagerError> {
+            self.check_in(page, false)
+        }
+
+        fn check_in(&mut self, page: Page<K, V>, modified: bool) -> Result<(), PagerError> {
+            let state = self.state.get_mut();
+            if let Some(frame) = state.frames.get_mut(&page.id) {
+                if frame.page.is_some() {
+                    state.lru.remove(&frame.tick);
+                }
+                state.tick += 1;
+                frame.tick = state.tick;
+                frame.dirty |= modified;
+                state.lru.insert(state.tick, page.id);
+                frame.page = Some(Rc::new(page));
+                Ok(())
+            } else {
+                let pager = self.pager.get_mut();
+                state.admit(pager.as_mut(), self.capacity, Rc::new(page), modified)
+            }
+        }
+
+        // Drop the cached image of a page without writing it back.
+        pub fn discard(&mut self, id: u32) {
+            self.log_undo(id);
+            let state = self.state.get_mut();
+            if let Some(frame) = state.frames.remove(&id) {
+                if frame.page.is_some() {
+                    state.lru.remove(&frame.tick);
+                }
+            }
+        }
+
+        // Write all dirty pages back to the pager, in page order.
+        pub fn flush(&mut self) -> Result<(), PagerError> {
+            let state = self.state.get_mut();
+            let pager = self.pager.get_mut();
+            let mut ids: Vec<u32> = state
+                .frames
+                .iter()
+                .filter(|(_, f)| f.dirty)
+                .map(|(&id, _)| id)
+                .collect();
+            ids.sort();
+            for id in ids {
+                let frame = state.frames.get_mut(&id).unwrap();
+                match &frame.page {
+                    Some(page) => pager.write_page(page)?,
+                    None => panic!("page {} is checked out", id),
+                }
+                frame.dirty = false;
+                state.stats.writes += 1;
+            }
+            Ok(())
+        }
+
+        // Record the current image of a page in the latest undo log, unless it
+        // was already changed since that log was started. Pages that can't be
+        // read, as they're past the end of the store, didn't exist.
+        fn log_undo(&mut self, id: u32) {
+            let Some(undo) = self.undo.last_mut() else {
+                return;
             };
-            'outer: loop {
-                for i in idx..leaf.vals.len() {
-                    if leaf.keys[i] > *max {
-                        break 'outer;
+            if undo.contains_key(&id) {
+                return;
+            }
+            let cached = self.state.get_mut().frames.get(&id);
+            let image = match cached.and_then(|f| f.page.clone()) {
+                Some(page) => Some(page),
+                None => self.pager.get_mut().read_page(id).ok().map(Rc::new),
+            };
+            undo.insert(id, image);
+        }
+
+        // Start keeping an undo log of the pages changed from here on.
+        pub fn begin(&mut self) {
+            assert!(self.undo.is_empty());
+            self.undo.push(HashMap::new());
+        }
+
+        // Start a new undo log on top of the open ones, so the changes made
+        // from here on can be rolled back separately. Returns its level.
+        pub fn savepoint(&mut self) -> usize {
+            assert!(!self.undo.is_empty());
+            self.undo.push(HashMap::new());
+            self.undo.len() - 1
+        }
+
+        // Keep the changes made since begin, dropping the undo logs.
+        pub fn commit(&mut self) {
+            self.undo.clear();
+        }
+
+        // Fold the undo logs from level on into the one below it, keeping
+        // the older image of pages changed in both.
+        pub fn release_savepoint(&mut self, level: usize) {
+            assert!(level > 0);
+            for undo in self.undo.split_off(level) {
+                let below = self.undo.last_mut().unwrap();
+                for (id, image) in undo {
+                    below.entry(id).or_insert(image);
+                }
+            }
+        }
+
+        // Put back the images of the pages changed since the undo log at level
+        // was started, newest first, and drop the logs from there on. They're
+        // marked dirty, since newer images may have been written back.
+        pub fn rollback_to(&mut self, level: usize) -> Result<(), PagerError> {
+            // the logs are taken out so undoing isn't itself logged
+            let mut logs = mem::take(&mut self.undo);
+            let undone = logs.split_off(level);
+            for undo in undone.into_iter().rev() {
+                for (id, image) in undo {
+                    match image {
+                        Some(page) => self.check_in(Rc::unwrap_or_clone(page), true)?,
+                        None => self.discard(id),
                     }
-                    if !leaf.deleted[i] {
-                        kvs.push((leaf.keys[i].clone(), leaf.vals[i].clone()));
+                }
+            }
+            self.undo = logs;
+            Ok(())
+        }
+
+        pub fn pager_mut(&mut self) -> &mut Box<dyn Pager<K, V>> {
+            self.pager.get_mut()
+        }
+
+        pub fn stats(&self) -> PoolStats {
+            self.state.borrow().stats
+        }
+
+        // Change the number of pages that can be cached.
+        pub fn set_capacity(&mut self, capacity: usize) -> Result<(), PagerError> {
+            assert!(capacity > 0);
+            self.capacity = capacity;
+            let pager = self.pager.get_mut();
+            self.state.get_mut().evict(pager.as_mut(), capacity)
+        }
+    }
+
+    pub fn pack_bits(bits: &[bool]) -> Vec<u8> {
+        let len = bits.len().div_ceil(8);
+        let mut bs = vec![0x0; len];
+        // write bits in LE order
+        for i in 0..len {
+            for j in 0..8 {
+                if 8 * i + j >= bits.len() {
+                    break;
+                }
+                bs[i] ^= (bits[8 * i + j] as u8) << (7 - j);
+            }
+        }
+        bs
+    }
+
+    // inverse of pack_bits
+    pub fn unpack_bits(len: usize, bytes: &[u8]) -> Vec<bool> {
+        let mut bits: Vec<bool> = vec![];
+        for b in bytes.iter() {
+            for j in (0..8).rev() {
+                let bit = ((*b >> j) & 1) == 1;
+                bits.push(bit);
+            }
+        }
+        bits.truncate(len);
+        bits
+    }
+
+    /// Bytes taken by the keys of a tree, and the bytes saved by storing
+    /// the prefix leaf keys share once and by truncating separators.
+    /// Separators are compared to the last keys of the leaves they follow,
+    /// which is what they'd be untruncated.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct KeyStats {
+        pub leaf_bytes: usize,
+        pub prefix_saved: usize,
+        pub separator_bytes: usize,
+        pub separator_saved: usize,
+    }
+
+    // The size of the keys of a leaf, as they're added. Keys with prefix bytes
+    // may share a prefix, which is stored once when that takes fewer bytes
+    // than storing the keys whole.
+    #[derive(Debug, Clone, Default)]
+    struct KeysSize {
+        n: usize,
+        whole: usize,
+        // the first key's prefix bytes, and how much of them all keys share
+        first: Option<Vec<u8>>,
+        common: usize,
+        // the length of all keys' prefix bytes
+        prefixed: usize,
+    }
+
+    impl KeysSize {
+        fn push<K: Key>(&mut self, key: &K) {
+            self.n += 1;
+            self.whole += key.size();
+            let Some(bytes) = key.prefix_bytes() else {
+                return;
+            };
+            self.prefixed += bytes.len();
+            match &self.first {
+                Some(first) => {
+                    let common = first.iter().zip(bytes.iter()).take_while(|(a, b)| a == b);
+                    self.common = self.common.min(common.count());
+                }
+                None => {
+                    self.common = bytes.len();
+                    self.first = Some(bytes);
+                }
+            }
+        }
+
+        // The length of the shared prefix and the bytes saved, if the keys
+        // are stored with the prefix taken out. Each key then takes the
+        // length of the rest of its prefix bytes and the rest.
+        fn prefix(&self) -> Option<(usize, usize)> {
+            self.first.as_ref()?;
+            let common = self.common.min(u16::MAX as usize);
+            let compressed = common + self.n * 2 + self.prefixed - self.n * common;
+            (compressed < self.whole).then_some((common, self.whole - compressed))
+        }
+
+        fn saved(&self) -> usize {
+            self.prefix().map_or(0, |(_, saved)| saved)
+        }
+    }
+
+    impl<K: Key, V: Val> Page<K, V> {
+        // Return the first index <= key
+        fn find(&self, key: &K) -> usize {
+            let mut i = 0;
+            for k in self.keys.iter() {
+                if key > k {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            i
+        }
+
+        // Drop the entries of a leaf page that are marked deleted.
+        // Returns the overflow pages of the dropped vals, for the caller to free.
+        fn purge_deleted(&mut self) -> Vec<Option<u32>> {
+            let mut spilled = vec![];
+            let mut i = 0;
+            while i < self.keys.len() {
+                if !self.versions[i].is_live() {
+                    self.keys.remove(i);
+                    self.vals.remove(i);
+                    self.versions.remove(i);
+                    spilled.push(self.overflow.remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            spilled
+        }
+
+        // The number of live entries in the page, or under it for an
+        // interior page.
+        fn count(&self) -> usize {
+            match self.ptype {
+                PageType::Interior => self.counts.iter().sum(),
+                _ => self.versions.iter().filter(|v| v.is_live()).count(),
+            }
+        }
+
+        // The key to split the page at split_idx with, which goes up to the
+        // parent. Leaves are separated by a key as short as possible.
+        fn split_key(&self, split_idx: usize) -> K {
+            match self.ptype {
+                PageType::Leaf => separator(&self.keys[split_idx], &self.keys[split_idx + 1]),
+                _ => self.keys[split_idx].clone(),
+            }
+        }
+
+        // Bytes taken by entry i when serialized: its slot and its cell,
+        // which holds a key with its version and val or overflow pointer in
+        // a leaf. In an interior page, each key also takes a child ID.
+        fn entry_size(&self, i: usize) -> usize {
+            match self.ptype {
+                PageType::Leaf => {
+                    Self::leaf_entry_size(&self.keys[i], &self.vals[i], self.overflow[i].is_some())
+                }
+                _ => Self::interior_entry_size(&self.keys[i]),
+            }
+        }
+
+        fn leaf_entry_size(key: &K, val: &V, spilled: bool) -> usize {
+            let val_size = if spilled { 8 } else { val.size() };
+            2 + 16 + key.size() + val_size
+        }
+
+        fn interior_entry_size(key: &K) -> usize {
+            2 + key.size() + 4 + 8
+        }
+
+        fn entries_size(&self) -> usize {
+            (0..self.keys.len()).map(|i| self.entry_size(i)).sum()
+        }
+
+        // Bytes taken by a page of n entries apart from the entries themselves.
+        fn overhead(ptype: PageType, n: usize) -> usize {
+            match ptype {
+                // sibling links, the key prefix length and flag, and the
+                // deleted and spilled bitmaps
+                PageType::Leaf => PAGE_HEADER_LEN + 11 + 2 * n.div_ceil(8),
+                // the extra child and its count
+                _ => PAGE_HEADER_LEN + 4 + 8,
+            }
+        }
+
+        // The size of the page when serialized, which must fit in PAGE_SIZE.
+        fn byte_size(&self) -> usize {
+            Self::overhead(self.ptype, self.keys.len()) + self.stored_entries_size()
+        }
+
+        // Bytes taken by the entries once a leaf's key prefix is taken out.
+        fn stored_entries_size(&self) -> usize {
+            match self.ptype {
+                PageType::Leaf => self.entries_size() - self.keys_size().saved(),
+                _ => self.entries_size(),
+            }
+        }
+
+        fn keys_size(&self) -> KeysSize {
+            let mut size = KeysSize::default();
+            for k in self.keys.iter() {
+                size.push(k);
+            }
+            size
+        }
+
+        /// Pages are slotted: the fixed size fields come first, followed by a
+        /// slot per entry holding the u16 offset of the entry's cell. Cells are
+        /// packed at the end of the page, so keys and vals can be any length.
+        /// The byte layout of a page is as follows:
+        ///
+        /// Interior Page
+        ///  0-4    4          5-9         9-13      13-17
+        /// +----+-----------+------------+---------+----------+
+        /// | id | page type | cells start | key len | checksum |
+        /// +----+-----------+------------+---------+----------+
+        /// | children       | counts       | slots            |
+        /// +----------------+--------------+------------------+
+        /// | free space                    | cells: key      |
+        /// +-------------------------------+-----------------+
+        ///
+        /// Leaf Page
+        ///  0-4    4          5-9         9-13      13-17
+        /// +----+-----------+------------+---------+----------+
+        /// | id | page type | cells start | key len | checksum |
+        /// +----+-----------+------------+---------+----------+
+        /// | sibling | left sibling | prefixed | prefix len | prefix |
+        /// +---------+--------------+----------+------------+--------+
+        /// | deleted | spilled | slots                               |
+        /// +---------+---------+-------------------------------------+
+        /// | free space          | cells: created, deleted, key, val  |
+        /// +---------------------+------------------------------------+
+        ///
+        /// If the leaf is prefixed, the prefix its keys' prefix bytes share
+        /// is stored once, and each cell holds the u16 length and the rest of
+        /// its key's prefix bytes instead of the key.
+        ///
+        /// Each child of an interior page has a u64 count of the live entries
+        /// under it, so entries can be counted and found by rank.
+        ///
+        /// A leaf cell starts with the u64 ids of the transactions that
+        /// created and deleted the entry, the latter only meaningful if the
+        /// entry's deleted bit is set.
+        ///
+        /// A spilled val is stored in a chain of overflow pages, and the leaf
+        /// holds the first overflow page ID and the val's length in its place.
+        ///
+        /// The checksum is the CRC-32 of the whole page except the checksum field.
+        fn to_bytes(&self) -> [u8; PAGE_SIZE] {
+            let mut bytes = [0; PAGE_SIZE];
+            let id_bytes: [u8; 4] = self.id.to_le_bytes();
+            bytes[0..4].copy_from_slice(&id_bytes);
+            bytes[4] = self.ptype as u8;
+            let keys_len = u32::try_from(self.keys.len()).unwrap();
+            bytes[9..13].copy_from_slice(&keys_len.to_le_bytes());
+
+            let prefix = match self.ptype {
+                PageType::Leaf => self.keys_size().prefix(),
+                _ => None,
+            };
+            let mut i = PAGE_HEADER_LEN;
+            match self.ptype {
+                PageType::Interior => {
+                    assert_eq!(self.counts.len(), self.children.len());
+                    for c in self.children.iter() {
+                        bytes[i..(i + 4)].copy_from_slice(&c.to_le_bytes());
+                        i += 4;
+                    }
+                    for &n in self.counts.iter() {
+                        bytes[i..(i + 8)].copy_from_slice(&(n as u64).to_le_bytes());
+                        i += 8;
                     }
                 }
-                match leaf.sibling {
-                    Some(i) => {
-                        id = i;
+                PageType::Overflow => panic!("overflow pages are written by write_overflow"),
+                PageType::Leaf | PageType::Free => {
+                    assert_eq!(self.versions.len(), self.vals.len());
+                    assert_eq!(self.overflow.len(), self.vals.len());
+                    assert_eq!(self.vals.len(), self.keys.len());
+                    for sib in [self.sibling, self.left_sibling] {
+                        let sib = sib.unwrap_or(u32::MAX);
+                        bytes[i..(i + 4)].copy_from_slice(&sib.to_le_bytes());
+                        i += 4;
                     }
-                    None => {
-                        break;
+                    if let Some((common, _)) = prefix {
+                        let first = self.keys[0].prefix_bytes().unwrap();
+                        bytes[i] = 1;
+                        bytes[(i + 1)..(i + 3)].copy_from_slice(&(common as u16).to_le_bytes());
+                        bytes[(i + 3)..(i + 3 + common)].copy_from_slice(&first[..common]);
+                        i += common;
+                    }
+                    i += 3;
+
+                    let deleted: Vec<bool> = self.versions.iter().map(|v| !v.is_live()).collect();
+                    let spilled: Vec<bool> = self.overflow.iter().map(|o| o.is_some()).collect();
+                    for bits in [&deleted, &spilled] {
+                        let bits_bytes = pack_bits(bits);
+                        let bits_len = bits_bytes.len();
+                        bytes[i..(i + bits_len)].copy_from_slice(&bits_bytes);
+                        i += bits_len;
+                    }
+                }
+            }
+
+            // cells are written downwards from the end of the page
+            let mut cell = PAGE_SIZE;
+            for (j, k) in self.keys.iter().enumerate() {
+                let mut cell_bytes = vec![];
+                if self.ptype != PageType::Interior {
+                    let version = self.versions[j];
+                    cell_bytes.extend(version.created.to_le_bytes());
+                    cell_bytes.extend(version.deleted.unwrap_or(0).to_le_bytes());
+                }
+                cell_bytes.extend(match prefix {
+                    Some((common, _)) => {
+                        let rest = k.prefix_bytes().unwrap().split_off(common);
+                        let mut cell_bytes = (rest.len() as u16).to_le_bytes().to_vec();
+                        cell_bytes.extend(rest);
+                        cell_bytes
+                    }
+                    None => k.to_bytes(),
+                });
+                if self.ptype != PageType::Interior {
+                    let v_bytes = self.vals[j].to_bytes();
+                    match self.overflow[j] {
+                        Some(head) => {
+                            // the value is stored in overflow pages
+                            let len = u32::try_from(v_bytes.len()).unwrap();
+                            cell_bytes.extend(head.to_le_bytes());
+                            cell_bytes.extend(len.to_le_bytes());
+                        }
+                        None => cell_bytes.extend(v_bytes),
                     }
                 }
-                leaf = self.pager.read_page(id).unwrap();
-                idx = 0;
+                cell -= cell_bytes.len();
+                bytes[cell..(cell + cell_bytes.len())].copy_from_slice(&cell_bytes);
+                let slot = u16::try_from(cell).unwrap();
+                bytes[i..(i + 2)].copy_from_slice(&slot.to_le_bytes());
+                i += 2;
             }
-            kvs
+            assert!(i <= cell, "page {} overflows its cells", self.id);
+            let cells_start = u32::try_from(cell).unwrap();
+            bytes[5..9].copy_from_slice(&cells_start.to_le_bytes());
+
+            let checksum = page_checksum(&bytes);
+            bytes[13..17].copy_from_slice(&checksum.to_le_bytes());
+            bytes
         }
 
-        // Insert a key-val pair into the tree.
-        pub fn insert(&mut self, key: K, val: V) -> Result<(), DuplicateKeyError> {
-            let mut id = self.root_id;
-            let mut visited = vec![];
-            for _ in 0..self.depth {
-                visited.push(id);
-                let page = self.pager.read_page(id).unwrap();
-                let idx = page.find(&key);
-                id = page.children[idx];
+        // Decode a page read from storage, checking that it is intact
+        // and is the page with the given id.
+        fn from_stored_bytes(
+            id: u32,
+            bs: &[u8],
+            read_overflow: &dyn Fn(u32, usize) -> Result<Vec<u8>, PagerError>,
+        ) -> Result<Self, PagerError> {
+            if bs.len() != PAGE_SIZE {
+                return Err(PagerError::Checksum(id));
             }
+            let checksum = u32::from_le_bytes(bs[13..17].try_into().unwrap());
+            if checksum != page_checksum(bs) {
+                return Err(PagerError::Checksum(id));
+            }
+            match Self::from_bytes(bs, read_overflow) {
+                Ok((_, page)) if page.id == id => Ok(page),
+                Ok(_) => Err(PagerError::Checksum(id)),
+                Err(PagerError::Corrupt { source, .. }) => Err(PagerError::Corrupt { id, source }),
+                Err(err) => Err(err),
+            }
+        }
 
-            // attempt insert key-val in the leaf page
-            let mut page = self.pager.read_page(id).unwrap().clone();
-            let search = page.keys.binary_search(&key);
-            let idx = search.unwrap_or_else(|x| x);
-            if search.is_err() || (search.is_ok() && !self.is_unique) {
-                // key is not present, or duplicates are allowed:
-                // OK to insert
-                page.keys.insert(idx, key.clone());
-                page.vals.insert(idx, val);
-                page.deleted.insert(idx, false);
-            } else {
-                // duplicate key found on a unique tree:
-                // try to replace a deleted entry, otherwise error
-                if page.deleted[idx] {
-                    page.vals[idx] = val;
-                    page.deleted[idx] = false;
-                    self.pager.write_page(&page);
-                    return Ok(());
-                } else {
-                    return Err(DuplicateKeyError);
-                }
+        // Decode a page. Values spilled to overflow pages are read with
+        // read_overflow, given the first overflow page and the value's length.
+        fn from_bytes(
+            bs: &[u8],
+            read_overflow: &dyn Fn(u32, usize) -> Result<Vec<u8>, PagerError>,
+        ) -> Result<(usize, Self), PagerError> {
+            let id = match bs.get(0..4) {
+                Some(b) => u32::from_le_bytes(b.try_into().unwrap()),
+                None => 0,
+            };
+            let corrupt = |source| PagerError::Corrupt { id, source };
+            let slice = |i: usize, len: usize| {
+                bs.get(i..(i + len))
+                    .ok_or(corrupt(SerializeError::InvalidByteLen))
+            };
+            let u32_at = |i: usize| -> Result<u32, PagerError> {
+                Ok(u32::from_le_bytes(slice(i, 4)?.try_into().unwrap()))
+            };
+            let ptype = match slice(4, 1)?[0] {
+                t if t == PageType::Leaf as u8 => PageType::Leaf,
+                t if t == PageType::Interior as u8 => PageType::Interior,
+                t if t == PageType::Free as u8 => PageType::Free,
+                t => return Err(corrupt(SerializeError::InvalidType(t))),
+            };
+            let cells_start = u32_at(5)? as usize;
+            let keys_len_usize = u32_at(9)? as usize;
+            if keys_len_usize > PAGE_SIZE {
+                return Err(corrupt(SerializeError::InvalidByteLen));
             }
+            // the cell of entry j, which must lie within the cell area
+            let cell_at = |slot: usize| -> Result<&[u8], PagerError> {
+                let off = u16::from_le_bytes(slice(slot, 2)?.try_into().unwrap()) as usize;
+                if off < cells_start {
+                    return Err(corrupt(SerializeError::InvalidByteLen));
+                }
+                Ok(&bs[off..])
+            };
+
+            let mut i = PAGE_HEADER_LEN;
+            match ptype {
+                PageType::Interior => {
+                    let mut children = Vec::with_capacity(keys_len_usize + 1);
+                    for _ in 0..=keys_len_usize {
+                        children.push(u32_at(i)?);
+                        i += 4;
+                    }
+                    let mut counts = Vec::with_capacity(keys_len_usize + 1);
+                    for _ in 0..=keys_len_usize {
+                        let n = u64::from_le_bytes(slice(i, 8)?.try_into().unwrap());
+                        counts.push(n as usize);
+                        i += 8;
+                    }
+                    let mut keys = Vec::with_capacity(keys_len_usize);
+                    for _ in 0..keys_len_usize {
+                        let (_, key) = K::from_bytes(cell_at(i)?).map_err(corrupt)?;
+                        keys.push(key);
+                        i += 2;
+                    }
+
+                    Ok((
+                        i,
+                        Page {
+                            id,
+                            ptype,
+                            keys,
+                            children,
+                            counts,
+                            vals: vec![],
+                            versions: vec![],
+                            overflow: vec![],
+                            sibling: None,
+                            left_sibling: None,
+                        },
+                    ))
+                }
+                PageType::Leaf | PageType::Free | PageType::Overflow => {
+                    let sib_at = |i: usize| -> Result<Option<u32>, PagerError> {
+                        let sib_id = u32_at(i)?;
+                        Ok(if sib_id == u32::MAX {
+                            None
+                        } else {
+                            Some(sib_id)
+                        })
+                    };
+                    let sibling = sib_at(i)?;
+                    let left_sibling = sib_at(i + 4)?;
+                    i += 8;
+                    let prefixed = slice(i, 3)?;
+                    let prefix = match prefixed[0] {
+                        0 => None,
+                        _ => {
+                            let len = u16::from_le_bytes(prefixed[1..3].try_into().unwrap());
+                            Some(slice(i + 3, len as usize)?)
+                        }
+                    };
+                    i += 3 + prefix.map_or(0, |p| p.len());
+
+                    let bits_len = keys_len_usize.div_ceil(8);
+                    let deleted = unpack_bits(keys_len_usize, slice(i, bits_len)?);
+                    i += bits_len;
+                    let spilled = unpack_bits(keys_len_usize, slice(i, bits_len)?);
+                    i += bits_len;
+
+                    let mut keys = Vec::with_capacity(keys_len_usize);
+                    let mut vals = Vec::with_capacity(keys_len_usize);
+                    let mut overflow = Vec::with_capacity(keys_len_usize);
+                    let mut versions = Vec::with_capacity(keys_len_usize);
+                    for (&spill, &del) in spilled.iter().zip(deleted.iter()) {
+                        let cell = cell_at(i)?;
+                        i += 2;
+                        let (created, deleted) = match cell.get(0..16) {
+                            Some(b) => (
+                                u64::from_le_bytes(b[0..8].try_into().unwrap()),
+                                u64::from_le_bytes(b[8..16].try_into().unwrap()),
+                            ),
+                            None => return Err(corrupt(SerializeError::InvalidByteLen)),
+                        };
+                        versions.push(Version {
+                            created,
+                            deleted: Some(deleted).filter(|_| del),
+                        });
+                        let cell = &cell[16..];
+                        let (key_size, key) = match prefix {
+                            Some(prefix) => {
+                                let len = match cell.get(0..2) {
+                                    Some(b) => u16::from_le_bytes(b.try_into().unwrap()) as usize,
+                                    None => return Err(corrupt(SerializeError::InvalidByteLen)),
+                                };
+                                let rest = cell
+                                    .get(2..(2 + len))
+                                    .ok_or(corrupt(SerializeError::InvalidByteLen))?;
+                                let key = K::from_prefix_bytes(&[prefix, rest].concat())
+                                    .map_err(corrupt)?;
+                                (2 + len, key)
+                            }
+                            None => K::from_bytes(cell).map_err(corrupt)?,
+                        };
+                        keys.push(key);
+                        let cell = &cell[key_size..];
+                        if spill {
+                            let u32_in = |j: usize| match cell.get(j..(j + 4)) {
+                                Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+                                None => Err(corrupt(SerializeError::InvalidByteLen)),
+                            };
+                            let head = u32_in(0)?;
+                            let bytes = read_overflow(head, u32_in(4)? as usize)?;
+                            let (_, val) = V::from_bytes(&bytes).map_err(corrupt)?;
+                            vals.push(val);
+                            overflow.push(Some(head));
+                        } else {
+                            let (_, val) = V::from_bytes(cell).map_err(corrupt)?;
+                            vals.push(val);
+                            overflow.push(None);
+                        }
+                    }
+
+                    Ok((
+                        i,
+                        Page {
+                            id,
+                            ptype,
+                            keys,
+                            versions,
+                            overflow,
+                            sibling,
+                            left_sibling,
+                            vals,
+                            children: vec![],
+                            counts: vec![],
+                        },
+                    ))
+                }
+            }
+        }
+    }
+
+    /// The byte layout of an overflow page is as follows:
+    ///
+    ///  0-4    4          5-9        9-13       13-17
+    /// +----+-----------+----------+----------+----------+
+    /// | id | page type | next id  | data len | checksum |
+    /// +----+-----------+----------+----------+----------+
+    /// | data                                             |
+    /// +--------------------------------------------------+
+    fn overflow_to_bytes(id: u32, next: Option<u32>, data: &[u8]) -> [u8; PAGE_SIZE] {
+        let mut bytes = [0; PAGE_SIZE];
+        bytes[0..4].copy_from_slice(&id.to_le_bytes());
+        bytes[4] = PageType::Overflow as u8;
+        bytes[5..9].copy_from_slice(&next.unwrap_or(u32::MAX).to_le_bytes());
+        let len = u32::try_from(data.len()).unwrap();
+        bytes[9..13].copy_from_slice(&len.to_le_bytes());
+        bytes[PAGE_HEADER_LEN..(PAGE_HEADER_LEN + data.len())].copy_from_slice(data);
+        let checksum = page_checksum(&bytes);
+        bytes[13..17].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    // Decode the overflow page with the given id, returning the next page
+    // of the chain and the page's data.
+    fn overflow_from_bytes(id: u32, bs: &[u8]) -> Result<(Option<u32>, Vec<u8>), PagerError> {
+        let u32_at = |i: usize| u32::from_le_bytes(bs[i..(i + 4)].try_into().unwrap());
+        if bs.len() != PAGE_SIZE
+            || u32_at(13) != page_checksum(bs)
+            || u32_at(0) != id
+            || bs[4] != PageType::Overflow as u8
+        {
+            return Err(PagerError::Checksum(id));
+        }
+        let next = Some(u32_at(5)).filter(|&next| next != u32::MAX);
+        let len = u32_at(9) as usize;
+        match bs.get(PAGE_HEADER_LEN..(PAGE_HEADER_LEN + len)) {
+            Some(data) => Ok((next, data.to_vec())),
+            None => Err(PagerError::Corrupt {
+                id,
+                source: SerializeError::InvalidByteLen,
+            }),
+        }
+    }
+
+    // CRC-32 of a page except its checksum field.
+    fn page_checksum(bs: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(&bs[0..13]);
+        crc.update(&bs[17..]);
+        crc.finish()
+    }
+
+    // ------------------- BTree Implementation -------------------
+
+    /// BTree implements a B+Tree.
+    /// Each page has at most b children, where b is odd, and fits in PAGE_SIZE
+    /// bytes, whichever limit is reached first.
+    #[derive(Debug)]
+    pub struct BTree<K: Key, V: Val> {
+        b: usize,
+        is_unique: bool,
+        depth: usize,
+        root_id: u32,
+        next_id: u32,
+        free_head: Option<u32>,
+        free_count: u32,
+        soft_delete: bool,
+        pool: BufferPool<K, V>,
+        txn: Option<Txn>,
+        next_txn: u64,
+        snapshots: Vec<Snapshot>,
+        shadow: Option<Shadow>, // for copy-on-write trees
+    }
+
+    // An open transaction. The pages it changes are logged by the pool, and
+    // the tree's metadata is kept to be put back on rollback. Overflow pages
+    // don't go through the pool, so the ones it frees are only freed once it
+    // commits.
+    #[derive(Debug)]
+    struct Txn {
+        id: u64,
+        header: Header,
+        shadow: Option<Shadow>,
+        freed: Vec<u32>,
+        savepoints: Vec<Savepoint>,
+    }
+
+    // A named point in a transaction that can be rolled back to, with the
+    // tree's metadata and the number of overflow chains freed at that point.
+    // Its changes are logged at pool undo level `level`.
+    #[derive(Debug)]
+    struct Savepoint {
+        name: String,
+        level: usize,
+        header: Header,
+        shadow: Option<Shadow>,
+        n_freed: usize,
+    }
+
+    // The state of a copy-on-write tree, see BTree::open_shadow. The pages
+    // the last commit uses are never written to. A page is changed by
+    // copying it to a fresh page, allocated since the commit, and pointing
+    // its parent at the copy, which copies the parent in turn, up to the
+    // root. The pages of the last commit freed since are its garbage.
+    //
+    // Leaves aren't linked to their siblings, since a copied leaf would have
+    // to be relinked from both neighbours, and they copied too, so leaves
+    // are walked along their path instead.
+    #[derive(Debug, Clone)]
+    struct Shadow {
+        commit: u64,
+        fresh: HashSet<u32>,
+        free: Vec<u32>,              // pages no retained commit uses
+        garbage: Vec<u32>,           // pages of the last commit freed since
+        commits: VecDeque<Retained>, // oldest first
+        meta: Vec<u32>,              // pages of the last commit's metadata
+        meta_len: u32,
+        retention: Retention,
+    }
+
+    // A commit that can still be read. Its garbage is the pages that only it
+    // and older commits use, which are freed once it's no longer retained.
+    #[derive(Debug, Clone)]
+    struct Retained {
+        id: u64,
+        time: u64,
+        root_id: u32,
+        depth: u32,
+        garbage: Vec<u32>,
+    }
+
+    /// Commit is a commit that can still be read, with the time it was made
+    /// in seconds since the Unix epoch.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Commit {
+        pub id: u64,
+        pub time: u64,
+    }
+
+    /// Retention says which commits of a copy-on-write tree are kept
+    /// readable: the last `commits` of them, counting the latest, and any
+    /// made in the last `secs` seconds. Commits that fall out of it are
+    /// dropped at the next commit, and the pages only they used are reused.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Retention {
+        pub commits: usize,
+        pub secs: u64,
+    }
+
+    impl Default for Retention {
+        fn default() -> Self {
+            Retention {
+                commits: 8,
+                secs: 0,
+            }
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+
+    impl Shadow {
+        fn new(retention: Retention) -> Shadow {
+            Shadow {
+                commit: 0,
+                fresh: HashSet::new(),
+                free: vec![],
+                garbage: vec![],
+                commits: VecDeque::new(),
+                meta: vec![],
+                meta_len: 0,
+                retention,
+            }
+        }
+
+        // Whether anything changed since the last commit.
+        fn is_dirty(&self) -> bool {
+            !self.fresh.is_empty() || !self.garbage.is_empty()
+        }
+
+        // The metadata of a commit lists the retained commits and the free
+        // pages, followed by the pages released by the commit, which are free
+        // once it's written:
+        //
+        // | n commits | commit ... | n free | free page IDs ... |
+        //
+        // where each commit is
+        //
+        // | id | time | root id | depth | n garbage | garbage page IDs ... |
+        //
+        // in little endian, with counts and page IDs taking 4 bytes and
+        // ids and times 8.
+        fn meta_to_bytes(&self, released: &[u32]) -> Vec<u8> {
+            let mut bytes = vec![];
+            bytes.extend(u32::try_from(self.commits.len()).unwrap().to_le_bytes());
+            for c in self.commits.iter() {
+                bytes.extend(c.id.to_le_bytes());
+                bytes.extend(c.time.to_le_bytes());
+                bytes.extend(c.root_id.to_le_bytes());
+                bytes.extend(c.depth.to_le_bytes());
+                bytes.extend(u32::try_from(c.garbage.len()).unwrap().to_le_bytes());
+                c.garbage
+                    .iter()
+                    .for_each(|id| bytes.extend(id.to_le_bytes()));
+            }
+            let n_free = self.free.len() + released.len();
+            bytes.extend(u32::try_from(n_free).unwrap().to_le_bytes());
+            for id in self.free.iter().chain(released) {
+                bytes.extend(id.to_le_bytes());
+            }
+            bytes
+        }
+
+        // Decode metadata written by meta_to_bytes into the retained commits
+        // and the free pages. Returns None if it's cut short.
+        fn meta_from_bytes(bs: &[u8]) -> Option<(VecDeque<Retained>, Vec<u32>)> {
+            let mut i = 0;
+            let mut next = |n: usize| {
+                let field = bs.get(i..(i + n))?;
+                i += n;
+                Some(field.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64))
+            };
+            let n_commits = next(4)?;
+            let mut commits = VecDeque::new();
+            for _ in 0..n_commits {
+                let (id, time) = (next(8)?, next(8)?);
+                let (root_id, depth) = (next(4)? as u32, next(4)? as u32);
+                let n_garbage = next(4)?;
+                let garbage = (0..n_garbage).map(|_| next(4).map(|id| id as u32));
+                commits.push_back(Retained {
+                    id,
+                    time,
+                    root_id,
+                    depth,
+                    garbage: garbage.collect::<Option<_>>()?,
+                });
+            }
+            let n_free = next(4)?;
+            let free = (0..n_free).map(|_| next(4).map(|id| id as u32));
+            Some((commits, free.collect::<Option<_>>()?))
+        }
+
+        // Read the state of the copy-on-write tree the header was committed
+        // for, with nothing changed since.
+        fn read<K: Key, V: Val>(
+            pager: &dyn Pager<K, V>,
+            header: &Header,
+            retention: Retention,
+        ) -> Result<Shadow, PagerError> {
+            // like read_chain, keeping the IDs of the pages
+            let mut bytes = vec![];
+            let mut meta = vec![];
+            let mut next = header.meta_head;
+            while let Some(id) = next {
+                let (n, data) = pager.read_overflow(id)?;
+                bytes.extend(data);
+                meta.push(id);
+                next = n;
+            }
+            let bs = bytes.get(..header.meta_len as usize);
+            let (commits, free) =
+                bs.and_then(Self::meta_from_bytes)
+                    .ok_or_else(|| PagerError::Corrupt {
+                        id: header.meta_head.unwrap_or(u32::MAX),
+                        source: SerializeError::InvalidByteLen,
+                    })?;
+            Ok(Shadow {
+                commit: header.commit,
+                free,
+                commits,
+                meta,
+                meta_len: header.meta_len,
+                ..Shadow::new(retention)
+            })
+        }
+    }
+
+    // The latest savepoint with the name.
+    fn find_savepoint(savepoints: &[Savepoint], name: &str) -> Result<usize, BTreeError> {
+        savepoints
+            .iter()
+            .rposition(|sp| sp.name == name)
+            .ok_or_else(|| BTreeError::NoSavepoint(name.to_string()))
+    }
+
+    /// Snapshot is a read view of a BTree as it was when the snapshot was
+    /// taken. It sees the entries written by then, except by a transaction
+    /// that was still open, and none written later. A snapshot is taken with
+    /// BTree::snapshot and given back with BTree::release_snapshot, after
+    /// which the old versions only it could see are garbage.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Snapshot {
+        id: u64,
+        active: Option<u64>,
+    }
+
+    impl Snapshot {
+        // Whether the changes made by transaction txn are seen.
+        fn sees_txn(&self, txn: u64) -> bool {
+            txn <= self.id && Some(txn) != self.active
+        }
+
+        // Whether the entry with the version is seen.
+        fn sees(&self, version: &Version) -> bool {
+            self.sees_txn(version.created) && !version.deleted.is_some_and(|d| self.sees_txn(d))
+        }
+    }
+
+    impl<K: Key + 'static, V: Val + 'static> fmt::Display for BTree<K, V> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let vecs = self.traverse().map_err(|_| fmt::Error)?;
+            for (l, ids) in vecs.iter().enumerate() {
+                f.write_fmt(format_args!("LEVEL {:?}\n", l))?;
+
+                for &id in ids.iter() {
+                    let page = self.pool.fetch(id).map_err(|_| fmt::Error)?;
+                    f.write_fmt(format_args!("\t{:?}\n", page))?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<K: Key, V: Val> BTree<K, V> {
+        pub fn header(&self) -> Header {
+            Header {
+                page_size: PAGE_SIZE as u32,
+                key_size: u32::try_from(size_of::<K>()).unwrap(),
+                b: u32::try_from(self.b).unwrap(),
+                is_unique: self.is_unique,
+                depth: u32::try_from(self.depth).unwrap(),
+                root_id: self.root_id,
+                next_id: self.next_id,
+                free_head: self.free_head,
+                free_count: self.free_count,
+                next_txn: self.next_txn,
+                commit: self.shadow.as_ref().map_or(0, |s| s.commit),
+                meta_head: self.shadow.as_ref().and_then(|s| s.meta.first().copied()),
+                meta_len: self.shadow.as_ref().map_or(0, |s| s.meta_len),
+            }
+        }
+
+        // Write cached pages and then the header back to storage. While a
+        // transaction is open nothing is written, since it must not be
+        // committed to the log until the transaction is. A copy-on-write
+        // tree makes a new commit instead, if it changed.
+        pub fn flush(&mut self) -> Result<(), BTreeError> {
+            if self.txn.is_some() {
+                return Ok(());
+            }
+            if self.shadow.is_some() {
+                return self.write_commit();
+            }
+            self.pool.flush()?;
+            let header = self.header();
+            let pager = self.pool.pager_mut();
+            pager.write_header(&header)?;
+            pager.sync()?;
+            Ok(())
+        }
+
+        // Undo the changes made by the open transaction.
+        pub fn rollback(&mut self) -> Result<(), BTreeError> {
+            let txn = self.txn.take().ok_or(BTreeError::NoTransaction)?;
+            self.pool.rollback_to(0)?;
+            self.restore(&txn.header, txn.shadow);
+            Ok(())
+        }
+
+        // Mark a point in the open transaction that can be rolled back to.
+        // Names may be reused, the latest savepoint with a name is the one used.
+        pub fn savepoint(&mut self, name: &str) -> Result<(), BTreeError> {
+            let header = self.header();
+            let txn = self.txn.as_mut().ok_or(BTreeError::NoTransaction)?;
+            txn.savepoints.push(Savepoint {
+                name: name.to_string(),
+                level: self.pool.savepoint(),
+                header,
+                shadow: self.shadow.clone(),
+                n_freed: txn.freed.len(),
+            });
+            Ok(())
+        }
+
+        // Undo the changes made since the savepoint, and drop the savepoints
+        // made after it. The savepoint itself is kept, so it can be rolled
+        // back to again.
+        pub fn rollback_to(&mut self, name: &str) -> Result<(), BTreeError> {
+            let txn = self.txn.as_mut().ok_or(BTreeError::NoTransaction)?;
+            let i = find_savepoint(&txn.savepoints, name)?;
+            txn.savepoints.truncate(i + 1);
+            let sp = &txn.savepoints[i];
+            txn.freed.truncate(sp.n_freed);
+            let (header, shadow) = (sp.header.clone(), sp.shadow.clone());
+            self.pool.rollback_to(sp.level)?;
+            self.pool.savepoint();
+            self.restore(&header, shadow);
+            Ok(())
+        }
+
+        // Drop the savepoint and the ones made after it. Their changes stay
+        // part of the transaction.
+        pub fn release(&mut self, name: &str) -> Result<(), BTreeError> {
+            let txn = self.txn.as_mut().ok_or(BTreeError::NoTransaction)?;
+            let i = find_savepoint(&txn.savepoints, name)?;
+            self.pool.release_savepoint(txn.savepoints[i].level);
+            txn.savepoints.truncate(i);
+            Ok(())
+        }
+
+        // Take a snapshot of the tree as it is now. Until it's released, the
+        // versions of entries it sees are kept as the tree changes.
+        #[allow(dead_code)]
+        pub fn snapshot(&mut self) -> Snapshot {
+            let snap = Snapshot {
+                id: self.next_txn,
+                active: self.txn.as_ref().map(|txn| txn.id),
+            };
+            // later writes are stamped with a newer id
+            self.next_txn += 1;
+            self.snapshots.push(snap);
+            snap
+        }
+
+        #[allow(dead_code)]
+        pub fn release_snapshot(&mut self, snap: Snapshot) {
+            self.snapshots.retain(|s| *s != snap);
+        }
+
+        // Whether changes must keep the old versions of entries, since a
+        // snapshot may see them. Snapshots taken while a transaction is open
+        // see the tree as it was before it.
+        fn keeps_versions(&self) -> bool {
+            self.txn.is_some() || !self.snapshots.is_empty()
+        }
+
+        // The id stamped on entries written now: the open transaction's, or
+        // 0 when no snapshot could tell the write apart, as every snapshot
+        // taken later sees it.
+        fn write_id(&self) -> u64 {
+            match &self.txn {
+                Some(txn) => txn.id,
+                None if self.snapshots.is_empty() => 0,
+                None => self.next_txn,
+            }
+        }
+
+        // Whether every open snapshot, and every one taken from now on, sees
+        // the changes made by transaction txn.
+        fn seen_by_all(&self, txn: u64) -> bool {
+            self.txn.as_ref().is_none_or(|t| t.id != txn)
+                && self.snapshots.iter().all(|snap| snap.sees_txn(txn))
+        }
+
+        // Whether no snapshot sees the entry with the version, now or later.
+        fn is_garbage(&self, version: &Version) -> bool {
+            version
+                .deleted
+                .is_some_and(|d| d == version.created || self.seen_by_all(d))
+        }
+
+        // Put back the tree's metadata from a header, along with the state
+        // of a copy-on-write tree.
+        fn restore(&mut self, header: &Header, shadow: Option<Shadow>) {
+            self.depth = header.depth as usize;
+            self.root_id = header.root_id;
+            self.next_id = header.next_id;
+            self.free_head = header.free_head;
+            self.free_count = header.free_count;
+            self.shadow = shadow;
+        }
+
+        pub fn in_transaction(&self) -> bool {
+            self.txn.is_some()
+        }
+
+        // Flush and move all committed pages into the database file.
+        pub fn checkpoint(&mut self) -> Result<(), BTreeError> {
+            if self.txn.is_some() {
+                return Ok(());
+            }
+            self.flush()?;
+            self.pool.pager_mut().checkpoint()?;
+            Ok(())
+        }
+
+        // Commit a copy-on-write tree that changed: write its fresh pages,
+        // then the commit's metadata, and swap in the new root by writing the
+        // header. A crash before the header is written leaves the last commit
+        // in place, as none of its pages were written to. For the same
+        // reason, the pages the commit releases, which are the last commit's
+        // metadata and the garbage of commits no longer retained, are only
+        // reused after it.
+        fn write_commit(&mut self) -> Result<(), BTreeError> {
+            let shadow = self.shadow.as_ref().unwrap();
+            if !shadow.is_dirty() {
+                return Ok(());
+            }
+            self.pool.flush()?;
+            // the tree's state is only updated once the commit is written
+            let mut next = shadow.clone();
+            let time = now();
+            let garbage = mem::take(&mut next.garbage);
+            match next.commits.back_mut() {
+                Some(last) => last.garbage.extend(garbage),
+                None => next.free.extend(garbage),
+            }
+            next.commit += 1;
+            next.commits.push_back(Retained {
+                id: next.commit,
+                time,
+                root_id: self.root_id,
+                depth: u32::try_from(self.depth).unwrap(),
+                garbage: vec![],
+            });
+            let mut released = mem::take(&mut next.meta);
+            let keep = next.retention;
+            while next.commits.len() > keep.commits.max(1)
+                && time.saturating_sub(next.commits[0].time) >= keep.secs
+            {
+                released.extend(next.commits.pop_front().unwrap().garbage);
+            }
+
+            // the metadata takes free pages until they hold what's left of it
+            let mut next_id = self.next_id;
+            let mut bytes = next.meta_to_bytes(&released);
+            while next.meta.len() < bytes.len().div_ceil(OVERFLOW_DATA_LEN).max(1) {
+                let id = next.free.pop().unwrap_or_else(|| {
+                    next_id += 1;
+                    next_id - 1
+                });
+                next.meta.push(id);
+                bytes = next.meta_to_bytes(&released);
+            }
+            next.meta_len = u32::try_from(bytes.len()).unwrap();
+            next.fresh.clear();
+            next.free.extend(released);
+
+            let mut header = self.header();
+            header.next_id = next_id;
+            header.free_count = u32::try_from(next.free.len()).unwrap();
+            header.commit = next.commit;
+            header.meta_head = next.meta.first().copied();
+            header.meta_len = next.meta_len;
+            let pager = self.pool.pager_mut();
+            let mut chunks = bytes.chunks(OVERFLOW_DATA_LEN);
+            for (i, &id) in next.meta.iter().enumerate() {
+                let data = chunks.next().unwrap_or_default();
+                pager.write_overflow(id, next.meta.get(i + 1).copied(), data)?;
+            }
+            pager.write_header(&header)?;
+            pager.sync()?;
+            self.next_id = next_id;
+            self.free_count = header.free_count;
+            self.shadow = Some(next);
+            Ok(())
+        }
+    }
+
+    impl<K: Key, V: Val> Drop for BTree<K, V> {
+        fn drop(&mut self) {
+            // best effort, since errors can't be returned from drop. an open
+            // transaction is rolled back
+            if self.txn.is_some() {
+                let _ = self.rollback();
+            }
+            let _ = self.checkpoint();
+        }
+    }
+
+    impl<K: Key + 'static, V: Val + 'static> BTree<K, V> {
+        #[cfg(test)]
+        pub fn new(b: usize, is_unique: bool) -> BTree<K, V> {
+            // writing to a MemPager can't fail
+            Self::create(Box::new(MemPager::new()), b, is_unique, None).unwrap()
+        }
+
+        // Open the tree stored in the database file at path.
+        // If the file doesn't exist, a new empty tree is created with the given
+        // b and is_unique; otherwise they are read from the file header.
+        pub fn open<P: AsRef<Path>>(
+            path: P,
+            b: usize,
+            is_unique: bool,
+        ) -> Result<BTree<K, V>, BTreeError> {
+            let pager: FilePager<K, V> = FilePager::open(path)?;
+            Self::open_pager(Box::new(pager), b, is_unique, None)
+        }
+
+        // Open the copy-on-write tree stored in the database file at path,
+        // like open. Instead of going through a log, changes copy the pages
+        // from the leaf up to the root, and every flush commits by swapping
+        // in the new root. Earlier commits are kept readable as long as the
+        // retention says, since they're just older roots.
+        pub fn open_shadow<P: AsRef<Path>>(
+            path: P,
+            b: usize,
+            is_unique: bool,
+            retention: Retention,
+        ) -> Result<BTree<K, V>, BTreeError> {
+            let pager: ShadowPager<K, V> = ShadowPager::open(path)?;
+            Self::open_pager(Box::new(pager), b, is_unique, Some(retention))
+        }
+
+        // Open the copy-on-write tree in the database file at path as it was
+        // at commit id, read-only. Only the commits listed by commits can be
+        // opened.
+        pub fn open_shadow_at<P: AsRef<Path>>(path: P, id: u64) -> Result<BTree<K, V>, BTreeError> {
+            let pager: ShadowPager<K, V> = ShadowPager::open_read_only(path)?;
+            let Some(header) = pager.read_header()? else {
+                return Err(PagerError::NoCommit(id).into());
+            };
+            if header.commit == 0 {
+                return Err(PagerError::NotCopyOnWrite.into());
+            }
+            let shadow = Shadow::read(&pager, &header, Retention::default())?;
+            let commit = shadow.commits.iter().find(|c| c.id == id);
+            let commit = commit.ok_or(PagerError::NoCommit(id))?;
+            let (root_id, depth) = (commit.root_id, commit.depth as usize);
+            let mut bt = Self::from_header(Box::new(pager), header, Some(shadow))?;
+            (bt.root_id, bt.depth) = (root_id, depth);
+            Ok(bt)
+        }
+
+        // Open the tree on pager, or create it if the pager is empty. Trees
+        // with a retention are copy-on-write, and only open copy-on-write
+        // files.
+        fn open_pager(
+            pager: Box<dyn Pager<K, V>>,
+            b: usize,
+            is_unique: bool,
+            retention: Option<Retention>,
+        ) -> Result<BTree<K, V>, BTreeError> {
+            let Some(header) = pager.read_header()? else {
+                return Self::create(pager, b, is_unique, retention);
+            };
+            let shadow = match (header.commit, retention) {
+                (0, None) => None,
+                (0, Some(_)) => return Err(PagerError::NotCopyOnWrite.into()),
+                (_, None) => return Err(PagerError::CopyOnWrite.into()),
+                (_, Some(retention)) => Some(Shadow::read(pager.as_ref(), &header, retention)?),
+            };
+            Self::from_header(pager, header, shadow)
+        }
+
+        // Create an empty tree on top of the given pager.
+        fn create(
+            pager: Box<dyn Pager<K, V>>,
+            b: usize,
+            is_unique: bool,
+            retention: Option<Retention>,
+        ) -> Result<BTree<K, V>, BTreeError> {
+            assert_eq!(b % 2, 1);
+            assert!(b > 2);
+
+            let mut bt = BTree {
+                b,
+                is_unique,
+                pool: BufferPool::new(pager, DEFAULT_POOL_CAPACITY),
+                depth: 0,
+                root_id: 1,
+                next_id: 2,
+                free_head: None,
+                free_count: 0,
+                soft_delete: false,
+                txn: None,
+                next_txn: 1,
+                snapshots: vec![],
+                shadow: retention.map(Shadow::new),
+            };
+            if let Some(shadow) = &mut bt.shadow {
+                shadow.fresh.insert(bt.root_id);
+            }
+            bt.pool.put(Page {
+                id: 1,
+                keys: vec![],
+                vals: vec![],
+                children: vec![],
+                counts: vec![],
+                versions: vec![],
+                overflow: vec![],
+                ptype: PageType::Leaf,
+                sibling: None,
+                left_sibling: None,
+            })?;
+            bt.flush()?;
+            Ok(bt)
+        }
+
+        // Load an existing tree described by header.
+        fn from_header(
+            pager: Box<dyn Pager<K, V>>,
+            header: Header,
+            shadow: Option<Shadow>,
+        ) -> Result<BTree<K, V>, BTreeError> {
+            let expected = u32::try_from(size_of::<K>()).unwrap();
+            if header.key_size != expected {
+                return Err(PagerError::KeySizeMismatch {
+                    found: header.key_size,
+                    expected,
+                }
+                .into());
+            }
+            Ok(BTree {
+                b: header.b as usize,
+                is_unique: header.is_unique,
+                pool: BufferPool::new(pager, DEFAULT_POOL_CAPACITY),
+                depth: header.depth as usize,
+                root_id: header.root_id,
+                next_id: header.next_id,
+                free_head: header.free_head,
+                free_count: header.free_count,
+                soft_delete: false,
+                txn: None,
+                next_txn: header.next_txn,
+                snapshots: vec![],
+                shadow,
+            })
+        }
+
+        // Start a transaction. Changes made from here on are only committed
+        // to storage by commit, and can be undone with rollback.
+        pub fn begin(&mut self) -> Result<(), BTreeError> {
+            if self.txn.is_some() {
+                return Err(BTreeError::TransactionOpen);
+            }
+            self.flush()?;
+            self.pool.begin();
+            // the id is past the one stamped on writes made before, and the
+            // writes made after it are stamped with the next
+            self.next_txn += 2;
+            self.txn = Some(Txn {
+                id: self.next_txn - 1,
+                header: self.header(),
+                shadow: self.shadow.clone(),
+                freed: vec![],
+                savepoints: vec![],
+            });
+            Ok(())
+        }
+
+        // Commit the open transaction to storage.
+        pub fn commit(&mut self) -> Result<(), BTreeError> {
+            let txn = self.txn.take().ok_or(BTreeError::NoTransaction)?;
+            self.pool.commit();
+            for head in txn.freed {
+                self.free_overflow(Some(head))?;
+            }
+            self.flush()
+        }
+
+        // Change the number of pages the buffer pool may cache.
+        #[allow(dead_code)]
+        pub fn set_pool_capacity(&mut self, capacity: usize) -> Result<(), BTreeError> {
+            self.pool.set_capacity(capacity)?;
+            Ok(())
+        }
+
+        pub fn pool_stats(&self) -> PoolStats {
+            self.pool.stats()
+        }
+
+        // The commits the tree can be opened at, oldest first. Only
+        // copy-on-write trees keep them.
+        pub fn commits(&self) -> Vec<Commit> {
+            let Some(shadow) = &self.shadow else {
+                return vec![];
+            };
+            let commits = shadow.commits.iter();
+            commits
+                .map(|c| Commit {
+                    id: c.id,
+                    time: c.time,
+                })
+                .collect()
+        }
+
+        pub fn key_stats(&self) -> Result<KeyStats, BTreeError> {
+            let mut stats = KeyStats::default();
+            let levels = self.traverse()?;
+            let (leaves, interiors) = levels.split_last().unwrap();
+            for &id in interiors.iter().flatten() {
+                let page = self.pool.fetch(id)?;
+                stats.separator_bytes += page.keys.iter().map(|k| k.size()).sum::<usize>();
+            }
+            let mut untruncated = 0;
+            for (i, &id) in leaves.iter().enumerate() {
+                let page = self.pool.fetch(id)?;
+                let size = page.keys_size();
+                stats.leaf_bytes += size.whole - size.saved();
+                stats.prefix_saved += size.saved();
+                if i + 1 < leaves.len() {
+                    untruncated += page.keys.last().map_or(0, |k| k.size());
+                }
+            }
+            stats.separator_saved = untruncated.saturating_sub(stats.separator_bytes);
+            Ok(stats)
+        }
+
+        // Allocate a page ID, reusing a page from the free list if there is one.
+        pub(crate) fn alloc_page(&mut self) -> Result<u32, BTreeError> {
+            if let Some(shadow) = &mut self.shadow {
+                let id = shadow.free.pop().unwrap_or_else(|| {
+                    self.next_id += 1;
+                    self.next_id - 1
+                });
+                shadow.fresh.insert(id);
+                self.free_count = u32::try_from(shadow.free.len()).unwrap();
+                return Ok(id);
+            }
+            match self.free_head {
+                Some(id) => {
+                    let page = self.pool.fetch(id)?;
+                    assert_eq!(page.ptype, PageType::Free);
+                    self.free_head = page.sibling;
+                    self.free_count -= 1;
+                    Ok(id)
+                }
+                None => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    Ok(id)
+                }
+            }
+        }
+
+        // Put a page that's no longer referenced by the tree on the free list.
+        // A copy-on-write tree keeps the free list in memory, and only frees
+        // pages of the last commit once it's no longer retained.
+        pub(crate) fn free_page(&mut self, id: u32) -> Result<(), BTreeError> {
+            if let Some(shadow) = &mut self.shadow {
+                if shadow.fresh.contains(&id) {
+                    shadow.free.push(id);
+                    self.free_count += 1;
+                    self.pool.discard(id);
+                } else {
+                    shadow.garbage.push(id);
+                }
+                return Ok(());
+            }
+            self.pool.put(Page {
+                id,
+                keys: vec![],
+                vals: vec![],
+                children: vec![],
+                counts: vec![],
+                versions: vec![],
+                overflow: vec![],
+                ptype: PageType::Free,
+                sibling: self.free_head,
+                left_sibling: None,
+            })?;
+            self.free_head = Some(id);
+            self.free_count += 1;
+            Ok(())
+        }
+
+        // Check out page id to change it. A page of the last commit of a
+        // copy-on-write tree is copied to a fresh page first, which the
+        // caller points the page's parent at.
+        fn take_writable(&mut self, id: u32) -> Result<Page<K, V>, BTreeError> {
+            if self.shadow.as_ref().is_none_or(|s| s.fresh.contains(&id)) {
+                return Ok(self.pool.take(id)?);
+            }
+            let mut page = Rc::unwrap_or_clone(self.pool.fetch(id)?);
+            page.id = self.alloc_page()?;
+            self.free_page(id)?;
+            let copy = page.id;
+            self.pool.put(page)?;
+            Ok(self.pool.take(copy)?)
+        }
+
+        // Check out child idx of parent to change it, see take_writable.
+        fn take_child(
+            &mut self,
+            parent: &mut Page<K, V>,
+            idx: usize,
+        ) -> Result<Page<K, V>, BTreeError> {
+            let page = self.take_writable(parent.children[idx])?;
+            parent.children[idx] = page.id;
+            Ok(page)
+        }
+
+        // Make the pages from the root down to leaf id, along path as found
+        // by find_path, writable in place. A copy-on-write tree copies the
+        // ones its last commit uses, and path and id are updated to the
+        // copies.
+        fn shadow(&mut self, path: &mut [(u32, usize)], id: &mut u32) -> Result<(), BTreeError> {
+            let Some(shadow) = &self.shadow else {
+                return Ok(());
+            };
+            if shadow.fresh.contains(id) {
+                // the parents of a fresh page are fresh too
+                return Ok(());
+            }
+            let mut parent: Option<(Page<K, V>, usize)> = None;
+            for i in 0..=path.len() {
+                let old = path.get(i).map_or(*id, |&(id, _)| id);
+                let page = self.take_writable(old)?;
+                match parent.take() {
+                    Some((mut parent, idx)) if page.id != old => {
+                        parent.children[idx] = page.id;
+                        self.pool.put(parent)?;
+                    }
+                    Some((parent, _)) => self.pool.release(parent)?,
+                    None => self.root_id = page.id,
+                }
+                match path.get_mut(i) {
+                    Some((id, idx)) => {
+                        *id = page.id;
+                        parent = Some((page, *idx));
+                    }
+                    None => {
+                        *id = page.id;
+                        self.pool.release(page)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        // How full a page of the given type with n entries taking entry_bytes
+        // would be, as the larger of its share of the b - 1 keys allowed and
+        // its share of PAGE_SIZE. A page is split when this goes over 1, so
+        // pages of small entries fill up to the fanout b, and pages of wide
+        // entries fill up to the page size.
+        fn fill(&self, ptype: PageType, n: usize, entry_bytes: usize) -> f64 {
+            let bytes = Page::<K, V>::overhead(ptype, n) + entry_bytes;
+            f64::max(
+                n as f64 / (self.b - 1) as f64,
+                bytes as f64 / PAGE_SIZE as f64,
+            )
+        }
+
+        fn page_fill(&self, page: &Page<K, V>) -> f64 {
+            self.fill(page.ptype, page.keys.len(), page.stored_entries_size())
+        }
+
+        fn underfull(&self, page: &Page<K, V>) -> bool {
+            self.page_fill(page) < MIN_FILL
+        }
+
+        // Index of the key to split an overfull page at. The left page keeps
+        // the entries up to it, and for interior pages the key itself moves
+        // up to the parent. Pages that are too many bytes are split so that
+        // both halves take about as many bytes, otherwise at the middle key.
+        fn split_index(&self, page: &Page<K, V>) -> usize {
+            let n = page.keys.len();
+            if page.byte_size() <= PAGE_SIZE {
+                return (n - 1) / 2;
+            }
+            let total = page.entries_size();
+            let mut acc = 0;
+            for i in 0..n {
+                acc += page.entry_size(i);
+                if 2 * acc >= total {
+                    return i.clamp(1, n - 2);
+                }
+            }
+            unreachable!()
+        }
+
+        // Whether left and right, the child after it at index idx of parent,
+        // fit in one page when merged.
+        fn can_merge(
+            &self,
+            parent: &Page<K, V>,
+            idx: usize,
+            left: &Page<K, V>,
+            right: &Page<K, V>,
+        ) -> bool {
+            // the merged keys may share less of a prefix, so measure the
+            // merged page rather than adding up the two
+            let (mut parent, mut merged) = (parent.clone(), left.clone());
+            Self::merge(&mut parent, idx, &mut merged, right.clone());
+            self.page_fill(&merged) <= 1.0
+        }
+
+        // Write val to a chain of overflow pages if it's too large to store in
+        // a leaf, and return the first page of the chain.
+        fn spill(&mut self, val: &V) -> Result<Option<u32>, BTreeError> {
+            let bytes = val.to_bytes();
+            if bytes.len() <= MAX_INLINE_VAL {
+                return Ok(None);
+            }
+            let chunks: Vec<&[u8]> = bytes.chunks(OVERFLOW_DATA_LEN).collect();
+            let mut ids = Vec::with_capacity(chunks.len());
+            for _ in chunks.iter() {
+                let id = self.alloc_page()?;
+                // overflow pages don't go through the pool, so drop the
+                // cached free page
+                self.pool.discard(id);
+                ids.push(id);
+            }
+            let pager = self.pool.pager_mut();
+            for (i, chunk) in chunks.iter().enumerate() {
+                pager.write_overflow(ids[i], ids.get(i + 1).copied(), chunk)?;
+            }
+            Ok(Some(ids[0]))
+        }
+
+        // Free the chain of overflow pages holding a spilled val, if any.
+        fn free_overflow(&mut self, head: Option<u32>) -> Result<(), BTreeError> {
+            if let (Some(txn), Some(head)) = (&mut self.txn, head) {
+                txn.freed.push(head);
+                return Ok(());
+            }
+            let mut next = head;
+            while let Some(id) = next {
+                next = self.pool.pager_mut().read_overflow(id)?.0;
+                self.free_page(id)?;
+            }
+            Ok(())
+        }
+
+        // Rebuild the tree densely from its live entries, dropping soft deleted
+        // entries, and return the number of pages reclaimed. Leaves are filled
+        // and interior levels are built on top of them, rather than inserting
+        // the entries one at a time. Old versions are only kept while an open
+        // snapshot sees them.
+        pub fn rebuild(&mut self) -> Result<usize, BTreeError> {
+            // traverse leaf pages to collect kv's
+            let mut entries: Vec<(K, V, Version)> = Vec::new();
+            let mut spilled = vec![];
+            let (mut path, id) = self.first_leaf()?;
+            let mut next = Some(id);
+            while let Some(id) = next {
+                let page = self.pool.fetch(id)?;
+                for (i, k) in page.keys.iter().enumerate() {
+                    let mut version = page.versions[i];
+                    if self.is_garbage(&version) {
+                        continue;
+                    }
+                    // no snapshot needs to tell an entry seen by all apart
+                    if self.seen_by_all(version.created) {
+                        version.created = 0;
+                    }
+                    entries.push((k.clone(), page.vals[i].clone(), version));
+                }
+                spilled.extend(page.overflow.iter().copied());
+                next = self.leaf_after(&mut path, &page)?;
+            }
+
+            // the old pages are only freed once the new tree has replaced them,
+            // so a failed build leaves the tree as it was
+            let old_ids: Vec<u32> = self.traverse()?.into_iter().flatten().collect();
+            self.build(entries, 1.0)?;
+            for &id in old_ids.iter() {
+                self.free_page(id)?;
+            }
+            // live vals were spilled again as the leaves were rebuilt
+            for head in spilled {
+                self.free_overflow(head)?;
+            }
+
+            let n_pages = self.traverse()?.iter().map(|ids| ids.len()).sum::<usize>();
+            Ok(old_ids.len().saturating_sub(n_pages))
+        }
+
+        // Load sorted entries into an empty tree, building it bottom up with
+        // pages filled to fill, a fraction of their capacity between 0 and 1.
+        // Input that isn't sorted, or has duplicate keys on a unique tree, is
+        // rejected and leaves the tree empty.
+        pub fn bulk_load<I>(&mut self, entries: I, fill: f64) -> Result<(), BTreeError>
+        where
+            I: IntoIterator<Item = (K, V)>,
+        {
+            assert!(fill > 0.0 && fill <= 1.0);
+            if self.depth > 0 || !self.pool.fetch(self.root_id)?.keys.is_empty() {
+                return Err(BTreeError::NotEmpty);
+            }
+            let old_root = self.root_id;
+            let version = Version::new(self.write_id());
+            let entries = entries.into_iter().map(|(k, v)| (k, v, version));
+            self.build(entries, fill)?;
+            self.free_page(old_root)
+        }
+
+        // Build a tree from sorted entries and make it the current tree. The
+        // pages of the current tree are left alone for the caller to free. On
+        // error, the pages allocated and vals spilled so far are freed again.
+        fn build<I>(&mut self, entries: I, fill: f64) -> Result<(), BTreeError>
+        where
+            I: IntoIterator<Item = (K, V, Version)>,
+        {
+            let mut allocated = vec![];
+            let mut spilled = vec![];
+            match self.build_levels(entries, fill, &mut allocated, &mut spilled) {
+                Ok((root_id, depth)) => {
+                    self.root_id = root_id;
+                    self.depth = depth;
+                    Ok(())
+                }
+                Err(err) => {
+                    for id in allocated {
+                        self.free_page(id)?;
+                    }
+                    for head in spilled {
+                        self.free_overflow(Some(head))?;
+                    }
+                    Err(err)
+                }
+            }
+        }
+
+        // Write the leaf level for entries, then interior levels on top until
+        // there's a single root. Returns the root ID and depth.
+        fn build_levels<I>(
+            &mut self,
+            entries: I,
+            fill: f64,
+            allocated: &mut Vec<u32>,
+            spilled: &mut Vec<u32>,
+        ) -> Result<(u32, usize), BTreeError>
+        where
+            I: IntoIterator<Item = (K, V, Version)>,
+        {
+            let (min, max) = (self.b / 2, self.b - 1);
+            let target = ((fill * max as f64).round() as usize).clamp(min, max);
+            // leaves of wide entries are filled up to a share of the page size
+            let target_fill = fill.max(MIN_FILL);
+
+            // the last two leaves are held back, since the last may need
+            // entries from the one before it
+            // each page of a level is listed with the key bounding it and
+            // its number of entries
+            let mut level: Vec<(K, u32, usize)> = vec![];
+            let mut prev: Option<Page<K, V>> = None;
+            let mut cur = self.new_leaf(allocated)?;
+            let mut cur_bytes = 0;
+            for (key, val, version) in entries {
+                let last = match cur.keys.last() {
+                    Some(key) => Some((key, cur.versions.last().unwrap())),
+                    None => prev
+                        .as_ref()
+                        .map(|p| (p.keys.last().unwrap(), p.versions.last().unwrap())),
+                };
+                // a unique tree may hold old versions of a key next to its
+                // live entry
+                match last.map(|(last, v)| (key.cmp(last), v.is_live())) {
+                    Some((Ordering::Less, _)) => return Err(BTreeError::Unsorted),
+                    Some((Ordering::Equal, true)) if self.is_unique && version.is_live() => {
+                        return Err(BTreeError::DuplicateKey)
+                    }
+                    _ => {}
+                }
+                if key.size() > MAX_KEY_SIZE {
+                    return Err(BTreeError::KeyTooLarge);
+                }
+                let head = self.spill(&val)?;
+                spilled.extend(head);
+                let size = Page::<K, V>::leaf_entry_size(&key, &val, head.is_some());
+                let n = cur.keys.len();
+                let bytes = Page::<K, V>::overhead(PageType::Leaf, n + 1) + cur_bytes + size;
+                if n == target || (n > 0 && bytes as f64 > target_fill * PAGE_SIZE as f64) {
+                    let mut next = self.new_leaf(allocated)?;
+                    if self.shadow.is_none() {
+                        cur.sibling = Some(next.id);
+                        next.left_sibling = Some(cur.id);
+                    }
+                    if let Some(page) = prev.replace(std::mem::replace(&mut cur, next)) {
+                        let next_key = &prev.as_ref().unwrap().keys[0];
+                        let sep = separator(page.keys.last().unwrap(), next_key);
+                        level.push((sep, page.id, page.count()));
+                        self.pool.put(page)?;
+                    }
+                    cur_bytes = 0;
+                }
+                cur_bytes += size;
+                cur.overflow.push(head);
+                cur.keys.push(key);
+                cur.vals.push(val);
+                cur.versions.push(version);
+            }
+
+            let Some(mut page) = prev else {
+                // everything fit in one leaf, which is the root
+                let id = cur.id;
+                self.pool.put(cur)?;
+                return Ok((id, 0));
+            };
+            if self.page_fill(&cur) < MIN_FILL {
+                // merge the last two leaves, or split their entries evenly
+                page.keys.append(&mut cur.keys);
+                page.vals.append(&mut cur.vals);
+                page.versions.append(&mut cur.versions);
+                page.overflow.append(&mut cur.overflow);
+                let at = if self.page_fill(&page) <= 1.0 {
+                    page.keys.len()
+                } else {
+                    self.split_index(&page) + 1
+                };
+                cur.keys = page.keys.split_off(at);
+                cur.vals = page.vals.split_off(at);
+                cur.versions = page.versions.split_off(at);
+                cur.overflow = page.overflow.split_off(at);
+            }
+            if cur.keys.is_empty() {
+                level.push((page.keys.last().unwrap().clone(), page.id, page.count()));
+                page.sibling = None;
+                allocated.retain(|&id| id != cur.id);
+                self.free_page(cur.id)?;
+            } else {
+                let sep = separator(page.keys.last().unwrap(), &cur.keys[0]);
+                level.push((sep, page.id, page.count()));
+                level.push((cur.keys.last().unwrap().clone(), cur.id, cur.count()));
+                self.pool.put(cur)?;
+            }
+            self.pool.put(page)?;
+
+            let (min, max) = (self.b / 2 + 1, self.b);
+            let target = ((fill * max as f64).round() as usize).clamp(min, max);
+            let mut depth = 0;
+            while level.len() > 1 {
+                let sizes: Vec<usize> = level
+                    .iter()
+                    .map(|(key, _, _)| Page::<K, V>::interior_entry_size(key))
+                    .collect();
+                let mut children = level.into_iter();
+                level = vec![];
+                for n in self.page_sizes(&sizes, target, target_fill) {
+                    let mut page = Page {
+                        id: self.alloc_page()?,
+                        ptype: PageType::Interior,
+                        keys: Vec::with_capacity(n - 1),
+                        vals: vec![],
+                        versions: vec![],
+                        overflow: vec![],
+                        children: Vec::with_capacity(n),
+                        counts: Vec::with_capacity(n),
+                        sibling: None,
+                        left_sibling: None,
+                    };
+                    allocated.push(page.id);
+                    for (max_key, id, count) in children.by_ref().take(n) {
+                        page.keys.push(max_key);
+                        page.children.push(id);
+                        page.counts.push(count);
+                    }
+                    // the last child is bounded by the parent's separator
+                    let max_key = page.keys.pop().unwrap();
+                    level.push((max_key, page.id, page.count()));
+                    self.pool.put(page)?;
+                }
+                depth += 1;
+            }
+            Ok((level[0].1, depth))
+        }
+
+        fn new_leaf(&mut self, allocated: &mut Vec<u32>) -> Result<Page<K, V>, BTreeError> {
+            let id = self.alloc_page()?;
+            allocated.push(id);
+            Ok(Page {
+                id,
+                ptype: PageType::Leaf,
+                keys: vec![],
+                vals: vec![],
+                versions: vec![],
+                overflow: vec![],
+                children: vec![],
+                counts: vec![],
+                sibling: None,
+                left_sibling: None,
+            })
+        }
+
+        // Split the children of an interior level, with the given entry sizes,
+        // into pages of target children, or fewer when they'd fill more than
+        // target_fill of the page size. If the last page would be underfull,
+        // it's merged into the page before when they fit together, or their
+        // children are shared evenly otherwise.
+        fn page_sizes(&self, sizes: &[usize], target: usize, target_fill: f64) -> Vec<usize> {
+            // a page of n children has n - 1 keys, but each child is counted
+            // with a key, which overestimates its size a little
+            let ptype = PageType::Interior;
+            let fits =
+                |sizes: &[usize]| self.fill(ptype, sizes.len() - 1, sizes.iter().sum()) <= 1.0;
+            if fits(sizes) {
+                return vec![sizes.len()];
+            }
+            let mut pages = vec![];
+            let (mut start, mut bytes) = (0, 0);
+            for (i, &size) in sizes.iter().enumerate() {
+                let n = i - start;
+                let full = Page::<K, V>::overhead(ptype, n) + bytes + size;
+                if n == target || (n > 0 && full as f64 > target_fill * PAGE_SIZE as f64) {
+                    pages.push(n);
+                    start = i;
+                    bytes = 0;
+                }
+                bytes += size;
+            }
+            let n = sizes.len() - start;
+            if self.fill(ptype, n - 1, bytes) >= MIN_FILL {
+                pages.push(n);
+                return pages;
+            }
+            let prev = pages.pop().unwrap();
+            let both = &sizes[(start - prev)..];
+            if fits(both) {
+                pages.push(both.len());
+            } else {
+                // share the children evenly by count, or by size when they
+                // don't fit in a page as an even count
+                let total = both.len();
+                let mut at = total - total / 2;
+                if !fits(&both[..at]) || !fits(&both[at..]) {
+                    let half: usize = both.iter().sum::<usize>() / 2;
+                    let mut acc = 0;
+                    at = both
+                        .iter()
+                        .take_while(|&&size| {
+                            acc += size;
+                            acc < half
+                        })
+                        .count();
+                    at = at.clamp(2, total - 2);
+                }
+                pages.push(at);
+                pages.push(total - at);
+            }
+            pages
+        }
+
+        // Find the leftmost leaf, along with its path like find_path.
+        #[allow(clippy::type_complexity)]
+        fn first_leaf(&self) -> Result<(Vec<(u32, usize)>, u32), BTreeError> {
+            let mut path = Vec::with_capacity(self.depth);
+            let mut id = self.root_id;
+            for _ in 0..self.depth {
+                path.push((id, 0));
+                id = self.pool.fetch(id)?.children[0];
+            }
+            Ok((path, id))
+        }
+
+        // Return the value associated with key, or None if it doesn't exist.
+        // If there are multiple values associated with the key, the first is
+        // returned.
+        pub fn find(&self, key: &K) -> Result<Option<V>, BTreeError> {
+            Self::find_with(self.cursor(), key)
+        }
+
+        // Return the value associated with key as the snapshot sees it.
+        #[allow(dead_code)]
+        pub fn find_at(&self, key: &K, snap: &Snapshot) -> Result<Option<V>, BTreeError> {
+            Self::find_with(self.cursor_at(snap), key)
+        }
+
+        fn find_with(mut cursor: Cursor<'_, K, V>, key: &K) -> Result<Option<V>, BTreeError> {
+            if cursor.seek(key)? && cursor.key() == Some(key) {
+                return Ok(cursor.val().cloned());
+            }
+            Ok(None)
+        }
+
+        // Return every live value associated with key, in the order they're
+        // stored. A run of duplicates may continue across several leaves.
+        #[allow(dead_code)]
+        pub fn find_all(&self, key: &K) -> Result<Vec<V>, BTreeError> {
+            Ok(self
+                .find_range(key, key)?
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect())
+        }
+
+        // Find key-value pairs where the min <= key <= max.
+        pub fn find_range(&self, min: &K, max: &K) -> Result<Vec<(K, V)>, BTreeError> {
+            Self::find_range_with(self.cursor(), min, max)
+        }
+
+        // Find key-value pairs where min <= key <= max as the snapshot sees
+        // them.
+        #[allow(dead_code)]
+        pub fn find_range_at(
+            &self,
+            min: &K,
+            max: &K,
+            snap: &Snapshot,
+        ) -> Result<Vec<(K, V)>, BTreeError> {
+            Self::find_range_with(self.cursor_at(snap), min, max)
+        }
+
+        fn find_range_with(
+            mut cursor: Cursor<'_, K, V>,
+            min: &K,
+            max: &K,
+        ) -> Result<Vec<(K, V)>, BTreeError> {
+            let mut kvs = vec![];
+            let mut valid = cursor.seek(min)?;
+            while valid {
+                let key = cursor.key().unwrap();
+                if key > max {
+                    break;
+                }
+                kvs.push((key.clone(), cursor.val().unwrap().clone()));
+                valid = cursor.next()?;
+            }
+            Ok(kvs)
+        }
+
+        // Find key-value pairs where min <= key <= max, in descending order.
+        // Leaves are walked right to left through their left sibling links.
+        #[allow(dead_code)]
+        pub fn find_range_rev(&self, min: &K, max: &K) -> Result<Vec<(K, V)>, BTreeError> {
+            let mut kvs = vec![];
+            let (mut path, id) = self.find_path(max)?;
+            let mut leaf = self.pool.fetch(id)?;
+            // duplicates of max may continue into the following leaves
+            let mut ahead = path.clone();
+            while let Some(sid) = self.leaf_after(&mut ahead, &leaf)? {
+                let next = self.pool.fetch(sid)?;
+                if next.keys.first().is_none_or(|k| k > max) {
+                    break;
+                }
+                (path, leaf) = (ahead.clone(), next);
+            }
+            let mut idx = leaf.keys.partition_point(|k| k <= max);
+            'outer: loop {
+                for i in (0..idx).rev() {
+                    if leaf.keys[i] < *min {
+                        break 'outer;
+                    }
+                    if leaf.versions[i].is_live() {
+                        kvs.push((leaf.keys[i].clone(), leaf.vals[i].clone()));
+                    }
+                }
+                leaf = match self.leaf_before(&mut path, &leaf)? {
+                    Some(id) => self.pool.fetch(id)?,
+                    None => break,
+                };
+                idx = leaf.keys.len();
+            }
+            Ok(kvs)
+        }
+
+        // The number of live entries in the tree.
+        pub fn count(&self) -> Result<usize, BTreeError> {
+            Ok(self.pool.fetch(self.root_id)?.count())
+        }
+
+        // The number of entries where min <= key <= max. Entries before each
+        // bound are counted from the subtree counts on the way down to it,
+        // rather than walking the range.
+        #[allow(dead_code)]
+        pub fn count_range(&self, min: &K, max: &K) -> Result<usize, BTreeError> {
+            let upto = self.count_before(|k| k <= max)?;
+            Ok(upto.saturating_sub(self.rank(min)?))
+        }
+
+        // The number of entries with a key < key, which is the position key
+        // has or would have in the tree.
+        pub fn rank(&self, key: &K) -> Result<usize, BTreeError> {
+            self.count_before(|k| k < key)
+        }
+
+        // The entry at position i in key order, counting from 0.
+        #[allow(dead_code)]
+        pub fn nth(&self, i: usize) -> Result<Option<(K, V)>, BTreeError> {
+            let mut cursor = self.cursor();
+            if !cursor.seek_nth(i)? {
+                return Ok(None);
+            }
+            Ok(Some((
+                cursor.key().unwrap().clone(),
+                cursor.val().unwrap().clone(),
+            )))
+        }
+
+        // Find the leaf holding the entry at position i and the entry's index
+        // in it, along with the path to the leaf as in find_path. When i is
+        // out of range, the index is past the end of the last leaf.
+        #[allow(clippy::type_complexity)]
+        fn find_nth(&self, i: usize) -> Result<(Vec<(u32, usize)>, u32, usize), BTreeError> {
+            let (path, id, i) = self.nth_path(i)?;
+            let leaf = self.pool.fetch(id)?;
+            let mut live = (0..leaf.keys.len()).filter(|&j| leaf.versions[j].is_live());
+            let idx = live.nth(i).unwrap_or(leaf.keys.len());
+            Ok((path, id, idx))
+        }
+
+        // Find the path to the leaf holding the entry at position i, without
+        // reading the leaf, and the entry's position among its live entries.
+        #[allow(clippy::type_complexity)]
+        fn nth_path(&self, mut i: usize) -> Result<(Vec<(u32, usize)>, u32, usize), BTreeError> {
+            let mut path = Vec::with_capacity(self.depth);
+            let mut id = self.root_id;
+            for _ in 0..self.depth {
+                let page = self.pool.fetch(id)?;
+                let mut idx = 0;
+                while idx + 1 < page.counts.len() && i >= page.counts[idx] {
+                    i -= page.counts[idx];
+                    idx += 1;
+                }
+                path.push((id, idx));
+                id = page.children[idx];
+            }
+            Ok((path, id, i))
+        }
+
+        // The number of live entries whose keys satisfy before, which holds
+        // for keys up to some bound and not after it.
+        fn count_before(&self, before: impl Fn(&K) -> bool) -> Result<usize, BTreeError> {
+            let mut n = 0;
+            let mut id = self.root_id;
+            for _ in 0..self.depth {
+                let page = self.pool.fetch(id)?;
+                // the children before idx only hold keys up to their separator
+                let idx = page.keys.partition_point(&before);
+                n += page.counts[..idx].iter().sum::<usize>();
+                id = page.children[idx];
+            }
+            let leaf = self.pool.fetch(id)?;
+            let end = leaf.keys.partition_point(&before);
+            Ok(n + leaf.versions[..end].iter().filter(|v| v.is_live()).count())
+        }
+
+        // Insert a key-val pair into the tree.
+        pub fn insert(&mut self, key: K, val: V) -> Result<(), BTreeError> {
+            if key.size() > MAX_KEY_SIZE {
+                return Err(BTreeError::KeyTooLarge);
+            }
+            let (mut path, mut id) = self.find_path(&key)?;
+            if self.is_unique && self.has_live(id, &key)? {
+                return Err(BTreeError::DuplicateKey);
+            }
+            self.shadow(&mut path, &mut id)?;
+
+            // attempt insert key-val in the leaf page
+            let mut page = self.pool.take(id)?;
+            let search = page.keys.binary_search(&key);
+            let idx = search.unwrap_or_else(|x| x);
+            let version = Version::new(self.write_id());
+            // a deleted entry of key on a unique tree is replaced, unless a
+            // snapshot may still see it
+            let replace = search.is_ok() && self.is_unique && !self.keeps_versions();
+            if replace {
+                // the new val may be larger, so the page can still
+                // need a split
+                self.free_overflow(page.overflow[idx])?;
+                page.overflow[idx] = self.spill(&val)?;
+                page.vals[idx] = val;
+                page.versions[idx] = version;
+            } else {
+                page.overflow.insert(idx, self.spill(&val)?);
+                page.keys.insert(idx, key.clone());
+                page.vals.insert(idx, val);
+                page.versions.insert(idx, version);
+            }
+
+            self.adjust_counts(&path, 1)?;
+
+            // since we inserted one entry, we can garbage collect one entry
+            if !replace {
+                if let Some(i) = page.versions.iter().rposition(|v| self.is_garbage(v)) {
+                    page.versions.remove(i);
+                    page.keys.remove(i);
+                    page.vals.remove(i);
+                    self.free_overflow(page.overflow.remove(i))?;
+                }
+            }
+
+            if self.page_fill(&page) <= 1.0 {
+                self.pool.put(page)?;
+                return Ok(());
+            }
+            // try to overflow to a sibling first
+            let mut visited: Vec<u32> = path.iter().map(|&(id, _)| id).collect();
+            if let Some(par_id) = visited.pop() {
+                if self.overflow_to_sibling(&mut page, par_id)? {
+                    self.pool.put(page)?;
+                    // the new separator may not fit in the parent
+                    let parent = self.pool.take(par_id)?;
+                    return self.split_up(parent, visited);
+                }
+                visited.push(par_id);
+            }
+            self.split_up(page, visited)
+        }
+
+        // Whether key has a live entry, given the leaf find_path leads to for
+        // it. A unique tree may still hold several versions of key, and their
+        // run may continue past the leaf.
+        fn has_live(&self, id: u32, key: &K) -> Result<bool, BTreeError> {
+            let leaf = self.pool.fetch(id)?;
+            let start = leaf.keys.partition_point(|k| k < key);
+            let end = start + leaf.keys[start..].iter().take_while(|k| *k == key).count();
+            if (start..end).any(|i| leaf.versions[i].is_live()) {
+                return Ok(true);
+            }
+            if end < leaf.keys.len() {
+                return Ok(false);
+            }
+            Ok(self.find(key)?.is_some())
+        }
+
+        // Replace the val of key and return the previous val. With duplicate
+        // keys, every entry of key gets val and the first previous val is
+        // returned.
+        pub fn update(&mut self, key: &K, val: V) -> Result<V, BTreeError> {
+            let mut prev = None;
+            self.update_range(key, key, |_, v| {
+                let old = std::mem::replace(v, val.clone());
+                prev.get_or_insert(old);
+            })?;
+            prev.ok_or(BTreeError::KeyNotFound)
+        }
+
+        // Insert key-val, or replace the val if key is already present, and
+        // return the previous val if there was one.
+        pub fn upsert(&mut self, key: K, val: V) -> Result<Option<V>, BTreeError> {
+            match self.update(&key, val.clone()) {
+                Ok(prev) => Ok(Some(prev)),
+                Err(BTreeError::KeyNotFound) => self.insert(key, val).map(|_| None),
+                Err(err) => Err(err),
+            }
+        }
+
+        // Apply f to the val of key in place and return how many entries
+        // were modified.
+        pub fn modify<F>(&mut self, key: &K, mut f: F) -> Result<usize, BTreeError>
+        where
+            F: FnMut(&mut V),
+        {
+            match self.update_range(key, key, |_, v| f(v))? {
+                0 => Err(BTreeError::KeyNotFound),
+                n_modified => Ok(n_modified),
+            }
+        }
+
+        // Split page if it's overfull, given the IDs of its ancestors from the
+        // root down, and propagate the split upward as long as the parents
+        // overflow with the split keys. The root splits into a new root.
+        fn split_up(
+            &mut self,
+            mut page: Page<K, V>,
+            mut ancestors: Vec<u32>,
+        ) -> Result<(), BTreeError> {
+            while self.page_fill(&page) > 1.0 {
+                match ancestors.pop() {
+                    Some(par_id) => {
+                        let mut parent = self.pool.take(par_id)?;
+                        let sibling = self.split_page(&mut page, &mut parent)?;
+                        self.pool.put(page)?;
+                        self.pool.put(sibling)?;
+                        page = parent;
+                    }
+                    None => {
+                        // split root
+                        assert_eq!(self.root_id, page.id);
+                        let (sibling, root) = self.split_root(&mut page)?;
+
+                        self.pool.put(sibling)?;
+                        self.pool.put(root)?;
+                        break;
+                    }
+                }
+            }
+            self.pool.put(page)?;
+            Ok(())
+        }
+
+        // Attempt to move entries of an overfull leaf page to a sibling with the
+        // same parent, so that splitting can be put off until both are full.
+        // The right sibling is tried first, then the left. Returns whether
+        // entries were moved, in which case page no longer needs a split.
+        fn overflow_to_sibling(
+            &mut self,
+            page: &mut Page<K, V>,
+            parent_id: u32,
+        ) -> Result<bool, BTreeError> {
+            let mut parent = self.pool.take(parent_id)?;
+            let idx = parent.children.iter().position(|&c| c == page.id).unwrap();
+            for sib_idx in [idx + 1, idx.wrapping_sub(1)] {
+                let Some(&sib_id) = parent.children.get(sib_idx) else {
+                    continue;
+                };
+                let sibling = self.pool.fetch(sib_id)?;
+                // even out the two pages, moving entries while the sibling
+                // has room for them and is no fuller than the page
+                let (p, s) = (page.keys.len(), sibling.keys.len());
+                let (mut p_bytes, mut s_bytes) = (page.entries_size(), sibling.entries_size());
+                let mut mov = 0;
+                while mov < p {
+                    let i = if sib_idx > idx { p - 1 - mov } else { mov };
+                    let size = page.entry_size(i);
+                    let p_fill = self.fill(PageType::Leaf, p - mov - 1, p_bytes - size);
+                    let s_fill = self.fill(PageType::Leaf, s + mov + 1, s_bytes + size);
+                    if s_fill > 1.0 || s_fill > p_fill {
+                        break;
+                    }
+                    p_bytes -= size;
+                    s_bytes += size;
+                    mov += 1;
+                }
+                if mov == 0 || self.fill(PageType::Leaf, p - mov, p_bytes) > 1.0 {
+                    continue;
+                }
+                drop(sibling);
+                let mut sibling = self.take_child(&mut parent, sib_idx)?;
+                if sib_idx > idx {
+                    let at = p - mov;
+                    sibling.keys.splice(0..0, page.keys.drain(at..));
+                    sibling.vals.splice(0..0, page.vals.drain(at..));
+                    sibling.versions.splice(0..0, page.versions.drain(at..));
+                    sibling.overflow.splice(0..0, page.overflow.drain(at..));
+                    parent.keys[idx] = separator(page.keys.last().unwrap(), &sibling.keys[0]);
+                } else {
+                    sibling.keys.extend(page.keys.drain(..mov));
+                    sibling.vals.extend(page.vals.drain(..mov));
+                    sibling.versions.extend(page.versions.drain(..mov));
+                    sibling.overflow.extend(page.overflow.drain(..mov));
+                    parent.keys[sib_idx] = separator(sibling.keys.last().unwrap(), &page.keys[0]);
+                }
+                parent.counts[idx] = page.count();
+                parent.counts[sib_idx] = sibling.count();
+                self.pool.put(sibling)?;
+                self.pool.put(parent)?;
+                return Ok(true);
+            }
+            self.pool.release(parent)?;
+            Ok(false)
+        }
+
+        // Split the given page into two and promote a key its parent page.
+        // Mutates the page and parent and return the new right sibling.
+        fn split_page(
+            &mut self,
+            page: &mut Page<K, V>,
+            parent: &mut Page<K, V>,
+        ) -> Result<Page<K, V>, BTreeError> {
+            let split_idx = self.split_index(page);
+            let split_key = page.split_key(split_idx);
+            // allocate right child page. the current page becomes left child page
+            let sibling = self.divide_page(page, split_idx)?;
+
+            // insert left and right as parent's children. with duplicate keys
+            // the parent may already have split_key as a separator, so look
+            // the page up by ID
+            let idx = parent.children.iter().position(|&c| c == page.id).unwrap();
+            parent.keys.insert(idx, split_key);
+            parent.children.insert(idx, page.id);
+            parent.children[idx + 1] = sibling.id;
+            parent.counts.insert(idx, page.count());
+            parent.counts[idx + 1] = sibling.count();
+
+            Ok(sibling)
+        }
+
+        // Splits a page without a parent i.e. the root page.
+        // In this case a new root page is created along with the right sibling page.
+        // Returns (sibling , new root).
+        #[allow(clippy::type_complexity)]
+        fn split_root(
+            &mut self,
+            page: &mut Page<K, V>,
+        ) -> Result<(Page<K, V>, Page<K, V>), BTreeError> {
+            let split_idx = self.split_index(page);
+            let split_key = page.split_key(split_idx);
+            let sibling = self.divide_page(page, split_idx)?;
+            // current page was the root page; create a new root
+            let new_root: Page<K, V> = Page {
+                id: self.alloc_page()?,
+                keys: vec![split_key],
+                children: vec![page.id, sibling.id],
+                counts: vec![page.count(), sibling.count()],
+                vals: Vec::new(),
+                ptype: PageType::Interior,
+                sibling: None,
+                left_sibling: None,
+                versions: vec![],
+                overflow: vec![],
+            };
+            self.root_id = new_root.id;
+            self.depth += 1;
+            Ok((sibling, new_root))
+        }
+
+        // Helper function for page splitting: move the entries of page after
+        // split_idx into a new (right) sibling and returns the sibling.
+        fn divide_page(
+            &mut self,
+            page: &mut Page<K, V>,
+            split_idx: usize,
+        ) -> Result<Page<K, V>, BTreeError> {
+            // allocate right child page. the current page becomes left child page
+            let mut r_page = Page {
+                id: self.alloc_page()?,
+                keys: Vec::with_capacity(split_idx),
+                vals: Vec::with_capacity(split_idx),
+                versions: Vec::with_capacity(split_idx),
+                overflow: Vec::with_capacity(split_idx),
+                children: vec![],
+                counts: vec![],
+                ptype: page.ptype,
+                sibling: page.sibling,
+                left_sibling: None,
+            };
+            r_page.keys = page.keys.drain((split_idx + 1)..).collect();
+
+            if page.ptype == PageType::Leaf {
+                r_page.vals = page.vals.drain((split_idx + 1)..).collect();
+                r_page.versions = page.versions.drain((split_idx + 1)..).collect();
+                r_page.overflow = page.overflow.drain((split_idx + 1)..).collect();
+                if self.shadow.is_none() {
+                    page.sibling = Some(r_page.id);
+                    r_page.left_sibling = Some(page.id);
+                    if let Some(id) = r_page.sibling {
+                        let mut next = self.pool.take(id)?;
+                        next.left_sibling = Some(r_page.id);
+                        self.pool.put(next)?;
+                    }
+                }
+            } else {
+                r_page.children = page.children.drain((split_idx + 1)..).collect();
+                r_page.counts = page.counts.drain((split_idx + 1)..).collect();
+                // the split key moves up to the parent, so an interior page
+                // always has one more child than keys
+                page.keys.pop();
+            }
+            Ok(r_page)
+        }
+
+        // Delete all entries associated with key and return how many were deleted.
+        // Entries are removed from their leaf and the tree is rebalanced, unless
+        // soft delete is enabled or a snapshot may still see them, in which
+        // case they are only marked deleted.
+        pub fn delete(&mut self, key: &K) -> Result<usize, BTreeError> {
+            match self.delete_range(key, key)? {
+                0 => Err(BTreeError::KeyNotFound),
+                n_deleted => Ok(n_deleted),
+            }
+        }
+
+        // Delete all entries where min <= key <= max and return how many were
+        // deleted, which may be none.
+        pub fn delete_range(&mut self, min: &K, max: &K) -> Result<usize, BTreeError> {
+            if min > max {
+                return Ok(0);
+            }
+            if self.soft_delete || self.keeps_versions() {
+                self.mark_deleted(min, max)
+            } else {
+                self.remove(min, max)
+            }
+        }
+
+        // Delete a single entry of key with val, leaving other entries of key
+        // alone. With several such entries, the first is deleted.
+        #[allow(dead_code)]
+        pub fn delete_entry(&mut self, key: &K, val: &V) -> Result<(), BTreeError>
+        where
+            V: PartialEq,
+        {
+            let (mut path, mut id) = self.find_path(key)?;
+            loop {
+                let leaf = self.pool.fetch(id)?;
+                let start = leaf.find(key);
+                let end = start + leaf.keys[start..].iter().take_while(|k| *k == key).count();
+                let Some(i) =
+                    (start..end).find(|&i| leaf.versions[i].is_live() && leaf.vals[i] == *val)
+                else {
+                    // the run of duplicates may continue in the next leaf
+                    if end < leaf.keys.len() {
+                        return Err(BTreeError::KeyNotFound);
+                    }
+                    match self.next_leaf(&mut path)? {
+                        Some(next) => id = next,
+                        None => return Err(BTreeError::KeyNotFound),
+                    }
+                    continue;
+                };
+                drop(leaf);
+                self.shadow(&mut path, &mut id)?;
+                let mut leaf = self.pool.take(id)?;
+                self.adjust_counts(&path, -1)?;
+                if self.soft_delete || self.keeps_versions() {
+                    leaf.versions[i].deleted = Some(self.write_id());
+                    self.pool.put(leaf)?;
+                    return Ok(());
+                }
+                leaf.keys.remove(i);
+                leaf.vals.remove(i);
+                leaf.versions.remove(i);
+                self.free_overflow(leaf.overflow.remove(i))?;
+                return self.rebalance(path, leaf);
+            }
+        }
+
+        // Apply f to the val of each entry where min <= key <= max, and return
+        // how many were updated. The leaves in range are walked once along
+        // their path. After a leaf has to be split as its vals grow, the walk
+        // looks up the next entry again by position, since a split may have
+        // split its ancestors too, and leaves left underfull are only
+        // rebalanced once the walk is done. While old versions are kept, the
+        // entries are deleted instead and inserted again with their new vals.
+        pub fn update_range<F>(&mut self, min: &K, max: &K, mut f: F) -> Result<usize, BTreeError>
+        where
+            F: FnMut(&K, &mut V),
+        {
+            if min > max {
+                return Ok(0);
+            }
+            if self.keeps_versions() {
+                let entries = self.find_range(min, max)?;
+                self.mark_deleted(min, max)?;
+                for (key, mut val) in entries.iter().cloned() {
+                    f(&key, &mut val);
+                    self.insert(key, val)?;
+                }
+                return Ok(entries.len());
+            }
+            let start = self.rank(min)?;
+            let mut n_updated = 0;
+            // positions of entries in leaves left underfull
+            let mut underfull = vec![];
+            let (mut path, mut id, mut idx) = self.find_nth(start)?;
+            loop {
+                let leaf = self.pool.fetch(id)?;
+                let end = idx + leaf.keys[idx..].iter().take_while(|k| *k <= max).count();
+                let more = end == leaf.keys.len();
+                let live = leaf.versions[idx..end].iter().any(|v| v.is_live());
+                drop(leaf);
+                if live {
+                    self.shadow(&mut path, &mut id)?;
+                    let mut leaf = self.pool.take(id)?;
+                    for i in idx..end {
+                        if !leaf.versions[i].is_live() {
+                            continue;
+                        }
+                        let mut val = leaf.vals[i].clone();
+                        f(&leaf.keys[i], &mut val);
+                        self.set_val(&mut leaf, i, val)?;
+                        n_updated += 1;
+                        // a split makes room for one grown val at a time
+                        if self.page_fill(&leaf) > 1.0 {
+                            break;
+                        }
+                    }
+                    if self.page_fill(&leaf) > 1.0 {
+                        self.split_up(leaf, path.iter().map(|&(id, _)| id).collect())?;
+                        // the walk goes on from the next entry, in whichever
+                        // half it ended up
+                        (path, id, idx) = self.find_nth(start + n_updated)?;
+                        continue;
+                    }
+                    if self.underfull(&leaf) {
+                        underfull.push(start + n_updated - 1);
+                    }
+                    self.pool.put(leaf)?;
+                }
+                if !more {
+                    break;
+                }
+                match self.next_leaf(&mut path)? {
+                    Some(next) => (id, idx) = (next, 0),
+                    None => break,
+                }
+            }
+            // rebalancing moves entries between leaves but keeps their
+            // positions, and a leaf may already have been merged into one
+            // that's no longer underfull
+            for i in underfull {
+                let (mut path, mut id, _) = self.nth_path(i)?;
+                if path.is_empty() || !self.underfull(&*self.pool.fetch(id)?) {
+                    continue;
+                }
+                self.shadow(&mut path, &mut id)?;
+                let leaf = self.pool.take(id)?;
+                self.rebalance(path, leaf)?;
+            }
+            Ok(n_updated)
+        }
+
+        // Replace the val of entry i of a leaf, spilling it to overflow pages
+        // if it's too large, and return the old val.
+        fn set_val(&mut self, leaf: &mut Page<K, V>, i: usize, val: V) -> Result<V, BTreeError> {
+            self.free_overflow(leaf.overflow[i])?;
+            leaf.overflow[i] = self.spill(&val)?;
+            Ok(std::mem::replace(&mut leaf.vals[i], val))
+        }
+
+        // Choose whether delete only marks entries as deleted.
+        #[allow(dead_code)]
+        pub fn set_soft_delete(&mut self, soft_delete: bool) {
+            self.soft_delete = soft_delete;
+        }
+
+        // Mark entries where min <= key <= max as deleted
+        fn mark_deleted(&mut self, min: &K, max: &K) -> Result<usize, BTreeError> {
+            let (mut path, mut id) = self.find_path(min)?;
+            let mut n_deleted = 0;
+            let txn = self.write_id();
+
+            loop {
+                let leaf = self.pool.fetch(id)?;
+                let idx = leaf.find(min);
+                let end = idx + leaf.keys[idx..].iter().take_while(|k| *k <= max).count();
+                let more = end == leaf.keys.len();
+                let n = leaf.versions[idx..end]
+                    .iter()
+                    .filter(|v| v.is_live())
+                    .count();
+                drop(leaf);
+                if n > 0 {
+                    self.shadow(&mut path, &mut id)?;
+                    let mut leaf = self.pool.take(id)?;
+                    for version in leaf.versions[idx..end].iter_mut() {
+                        if version.is_live() {
+                            version.deleted = Some(txn);
+                        }
+                    }
+                    self.pool.put(leaf)?;
+                    self.adjust_counts(&path, -(n as isize))?;
+                    n_deleted += n;
+                }
+                if !more {
+                    break;
+                }
+                // we may have to search the siblings
+                match self.next_leaf(&mut path)? {
+                    Some(next) => {
+                        id = next;
+                    }
+                    None => {
+                        break;
+                    }
+                }
+            }
+            Ok(n_deleted)
+        }
+
+        // Remove entries where min <= key <= max from the tree. Soft deleted
+        // entries in the affected leaves are dropped as well. The pages under
+        // the range are visited once, top down, and each parent fixes up its
+        // children after all of them in range are done, since a range leaves
+        // runs of empty leaves that can't be rebalanced one at a time.
+        fn remove(&mut self, min: &K, max: &K) -> Result<usize, BTreeError> {
+            let Some((n_deleted, root_id)) = self.remove_under(self.root_id, min, max)? else {
+                return Ok(0);
+            };
+            self.root_id = root_id;
+            // separators may have grown, in which case the root splits
+            let root = self.pool.take(self.root_id)?;
+            self.split_up(root, vec![])?;
+            // the root shrinks while it's left with one child
+            loop {
+                let root = self.pool.fetch(self.root_id)?;
+                if root.ptype != PageType::Interior || !root.keys.is_empty() {
+                    return Ok(n_deleted);
+                }
+                let (id, child) = (root.id, root.children[0]);
+                drop(root);
+                self.root_id = child;
+                self.depth -= 1;
+                self.free_page(id)?;
+            }
+        }
+
+        // Remove entries where min <= key <= max under page id and return how
+        // many live entries were removed, along with the page's ID, which
+        // changes when it's copied, or None if nothing under it changed. The
+        // page itself may be left underfull or overfull, for its parent to fix
+        // up.
+        #[allow(clippy::type_complexity)]
+        fn remove_under(
+            &mut self,
+            id: u32,
+            min: &K,
+            max: &K,
+        ) -> Result<Option<(usize, u32)>, BTreeError> {
+            let page = self.pool.fetch(id)?;
+            if page.ptype == PageType::Leaf {
+                let start = page.find(min);
+                let end = start + page.keys[start..].iter().take_while(|k| *k <= max).count();
+                drop(page);
+                if start == end {
+                    return Ok(None);
+                }
+                let mut leaf = self.take_writable(id)?;
+                let n = leaf.versions[start..end]
+                    .iter()
+                    .filter(|v| v.is_live())
+                    .count();
+                leaf.keys.drain(start..end);
+                leaf.vals.drain(start..end);
+                leaf.versions.drain(start..end);
+                let mut spilled: Vec<Option<u32>> = leaf.overflow.drain(start..end).collect();
+                spilled.extend(leaf.purge_deleted());
+                for head in spilled {
+                    self.free_overflow(head)?;
+                }
+                let id = leaf.id;
+                self.pool.put(leaf)?;
+                return Ok(Some((n, id)));
+            }
+            // a run of duplicates may continue past the separator equal to it
+            let lo = page.find(min);
+            let hi = page.keys.partition_point(|k| k <= max);
+            let children = page.children[lo..=hi].to_vec();
+            drop(page);
+            let mut removed = Vec::with_capacity(children.len());
+            for (i, child) in children.into_iter().enumerate() {
+                if let Some((n, child)) = self.remove_under(child, min, max)? {
+                    removed.push((lo + i, n, child));
+                }
+            }
+            if removed.is_empty() {
+                return Ok(None);
+            }
+            let mut page = self.take_writable(id)?;
+            for &(i, n, child) in removed.iter() {
+                page.counts[i] -= n;
+                page.children[i] = child;
+            }
+            self.fix_children(&mut page, lo, hi)?;
+            let id = page.id;
+            self.pool.put(page)?;
+            Ok(Some((removed.iter().map(|&(_, n, _)| n).sum(), id)))
+        }
+
+        // Fix up the children of parent from lo to hi, which may be underfull
+        // or, as their separators grew, overfull. An underfull child is merged
+        // with a sibling when the two fit in one page and borrows entries from
+        // one otherwise. Children of interior pages that merge or borrow meet
+        // new siblings, so those are fixed up in turn.
+        fn fix_children(
+            &mut self,
+            parent: &mut Page<K, V>,
+            lo: usize,
+            mut hi: usize,
+        ) -> Result<(), BTreeError> {
+            let mut i = lo;
+            while i <= hi && i < parent.children.len() {
+                let page = self.pool.fetch(parent.children[i])?;
+                let overfull = self.page_fill(&page) > 1.0;
+                if !overfull && (!self.underfull(&page) || parent.children.len() == 1) {
+                    i += 1;
+                    continue;
+                }
+                drop(page);
+                let mut page = self.take_child(parent, i)?;
+                if overfull {
+                    let sibling = self.split_page(&mut page, parent)?;
+                    self.pool.put(page)?;
+                    self.pool.put(sibling)?;
+                    (i, hi) = (i + 2, hi + 1);
+                    continue;
+                }
+                if i + 1 < parent.children.len() {
+                    let right = self.pool.fetch(parent.children[i + 1])?;
+                    if self.can_merge(parent, i, &page, &right) {
+                        // right is freed, so it's merged without a copy
+                        let right = Rc::unwrap_or_clone(right);
+                        let (id, at) = (right.id, page.children.len());
+                        Self::merge(parent, i, &mut page, right);
+                        self.relink_right(&page)?;
+                        self.fix_junction(&mut page, at)?;
+                        self.pool.put(page)?;
+                        self.free_page(id)?;
+                        hi = hi.saturating_sub(1).max(i);
+                        continue;
+                    }
+                }
+                if i > 0 {
+                    let left = self.pool.fetch(parent.children[i - 1])?;
+                    if self.can_merge(parent, i - 1, &left, &page) {
+                        drop(left);
+                        let mut left = self.take_child(parent, i - 1)?;
+                        let (id, at) = (page.id, left.children.len());
+                        Self::merge(parent, i - 1, &mut left, page);
+                        self.relink_right(&left)?;
+                        self.fix_junction(&mut left, at)?;
+                        self.pool.put(left)?;
+                        self.free_page(id)?;
+                        (i, hi) = (i - 1, hi - 1);
+                        continue;
+                    }
+                }
+                // neither sibling has room, so borrow from one that can spare
+                // entries. pages of wide entries may be left underfull
+                let mut n = 0;
+                if i + 1 < parent.children.len() {
+                    let mut right = self.take_child(parent, i + 1)?;
+                    let at = page.children.len();
+                    while self.underfull(&page) {
+                        Self::borrow_right(parent, i, &mut page, &mut right);
+                        if self.underfull(&right) {
+                            Self::borrow_left(parent, i + 1, &mut page, &mut right);
+                            break;
+                        }
+                        n += 1;
+                    }
+                    if n > 0 {
+                        self.fix_junction(&mut page, at)?;
+                    }
+                    self.pool.put(right)?;
+                } else {
+                    let mut left = self.take_child(parent, i - 1)?;
+                    while self.underfull(&page) {
+                        Self::borrow_left(parent, i, &mut left, &mut page);
+                        if self.underfull(&left) {
+                            Self::borrow_right(parent, i - 1, &mut left, &mut page);
+                            break;
+                        }
+                        n += 1;
+                    }
+                    if n > 0 {
+                        self.fix_junction(&mut page, n)?;
+                    }
+                    self.pool.put(left)?;
+                }
+                self.pool.put(page)?;
+                // the junction may have merged children, so look again
+                if n == 0 {
+                    i += 1;
+                }
+            }
+            Ok(())
+        }
+
+        // Fix up the children either side of index at of an interior page,
+        // which became siblings when entries were moved into it.
+        fn fix_junction(&mut self, page: &mut Page<K, V>, at: usize) -> Result<(), BTreeError> {
+            if page.ptype == PageType::Interior && at > 0 && at < page.children.len() {
+                self.fix_children(page, at - 1, at)?;
+            }
+            Ok(())
+        }
+
+        // Add delta to the count of each child taken along path, after the
+        // live entries of the leaf at its end changed by delta.
+        fn adjust_counts(&mut self, path: &[(u32, usize)], delta: isize) -> Result<(), BTreeError> {
+            for &(id, idx) in path {
+                let mut page = self.pool.take(id)?;
+                page.counts[idx] = page.counts[idx].checked_add_signed(delta).unwrap();
+                self.pool.put(page)?;
+            }
+            Ok(())
+        }
+
+        // Find the leaf for key, along with the interior pages visited on the
+        // way and the index of the child taken at each.
+        #[allow(clippy::type_complexity)]
+        fn find_path(&self, key: &K) -> Result<(Vec<(u32, usize)>, u32), BTreeError> {
+            let mut path = Vec::with_capacity(self.depth);
+            let mut id = self.root_id;
+            for _ in 0..self.depth {
+                let page = self.pool.fetch(id)?;
+                let idx = page.find(key);
+                path.push((id, idx));
+                id = page.children[idx];
+            }
+            Ok((path, id))
+        }
+
+        // Advance path to the next leaf to the right and return its ID.
+        fn next_leaf(&self, path: &mut Vec<(u32, usize)>) -> Result<Option<u32>, BTreeError> {
+            let depth = path.len();
+            while let Some((id, idx)) = path.pop() {
+                let page = self.pool.fetch(id)?;
+                if idx + 1 < page.children.len() {
+                    path.push((id, idx + 1));
+                    let mut child = page.children[idx + 1];
+                    while path.len() < depth {
+                        path.push((child, 0));
+                        child = self.pool.fetch(child)?.children[0];
+                    }
+                    return Ok(Some(child));
+                }
+            }
+            Ok(None)
+        }
+
+        // Move path to the next leaf to the left and return its ID.
+        fn prev_leaf(&self, path: &mut Vec<(u32, usize)>) -> Result<Option<u32>, BTreeError> {
+            let depth = path.len();
+            while let Some((id, idx)) = path.pop() {
+                if idx > 0 {
+                    path.push((id, idx - 1));
+                    let mut child = self.pool.fetch(id)?.children[idx - 1];
+                    while path.len() < depth {
+                        let page = self.pool.fetch(child)?;
+                        let last = page.children.len() - 1;
+                        path.push((child, last));
+                        child = page.children[last];
+                    }
+                    return Ok(Some(child));
+                }
+            }
+            Ok(None)
+        }
+
+        // Return the ID of the leaf to the right of leaf, following its
+        // sibling link. Leaves of copy-on-write trees aren't linked, so path
+        // is walked instead; it is left as it was for other trees.
+        fn leaf_after(
+            &self,
+            path: &mut Vec<(u32, usize)>,
+            leaf: &Page<K, V>,
+        ) -> Result<Option<u32>, BTreeError> {
+            match self.shadow {
+                None => Ok(leaf.sibling),
+                Some(_) => self.next_leaf(path),
+            }
+        }
+
+        // Return the ID of the leaf to the left of leaf, like leaf_after.
+        fn leaf_before(
+            &self,
+            path: &mut Vec<(u32, usize)>,
+            leaf: &Page<K, V>,
+        ) -> Result<Option<u32>, BTreeError> {
+            match self.shadow {
+                None => Ok(leaf.left_sibling),
+                Some(_) => self.prev_leaf(path),
+            }
+        }
+
+        // Fix up page after entries were removed from it, given the path from
+        // the root to its parent. An underfull page borrows entries from a
+        // sibling while the sibling can spare them and is merged with it
+        // otherwise, which removes an entry from the parent, so this continues
+        // up the path. Pages of wide entries that don't fit together are left
+        // as they are.
+        fn rebalance(
+            &mut self,
+            mut path: Vec<(u32, usize)>,
+            mut page: Page<K, V>,
+        ) -> Result<(), BTreeError> {
+            loop {
+                let Some((parent_id, idx)) = path.pop() else {
+                    // page is the root, which shrinks when left with one child
+                    if page.ptype == PageType::Interior && page.keys.is_empty() {
+                        self.root_id = page.children[0];
+                        self.depth -= 1;
+                        self.free_page(page.id)?;
+                    } else {
+                        self.pool.put(page)?;
+                    }
+                    return Ok(());
+                };
+                // separators may have grown, in which case the page splits
+                let ancestors = |path: &Vec<(u32, usize)>| path.iter().map(|&(id, _)| id).collect();
+                if !self.underfull(&page) {
+                    return self.split_up(page, ancestors(&path));
+                }
+
+                let mut parent = self.pool.take(parent_id)?;
+                if idx > 0 {
+                    let mut left = self.take_child(&mut parent, idx - 1)?;
+                    while self.underfull(&page) {
+                        Self::borrow_left(&mut parent, idx, &mut left, &mut page);
+                        if self.underfull(&left) {
+                            // left can't spare the entry, so give it back
+                            Self::borrow_right(&mut parent, idx - 1, &mut left, &mut page);
+                            break;
+                        }
+                    }
+                    if !self.underfull(&page) || !self.can_merge(&parent, idx - 1, &left, &page) {
+                        self.pool.put(left)?;
+                        self.pool.put(page)?;
+                        return self.split_up(parent, ancestors(&path));
+                    }
+                    let id = page.id;
+                    Self::merge(&mut parent, idx - 1, &mut left, page);
+                    self.relink_right(&left)?;
+                    self.pool.put(left)?;
+                    self.free_page(id)?;
+                } else {
+                    let mut right = self.take_child(&mut parent, idx + 1)?;
+                    while self.underfull(&page) {
+                        Self::borrow_right(&mut parent, idx, &mut page, &mut right);
+                        if self.underfull(&right) {
+                            Self::borrow_left(&mut parent, idx + 1, &mut page, &mut right);
+                            break;
+                        }
+                    }
+                    if !self.underfull(&page) || !self.can_merge(&parent, idx, &page, &right) {
+                        self.pool.put(page)?;
+                        self.pool.put(right)?;
+                        return self.split_up(parent, ancestors(&path));
+                    }
+                    let id = right.id;
+                    Self::merge(&mut parent, idx, &mut page, right);
+                    self.relink_right(&page)?;
+                    self.pool.put(page)?;
+                    self.free_page(id)?;
+                }
+                page = parent;
+            }
+        }
+
+        // Move the last entry of left to the front of page, its right sibling
+        // at index idx of parent.
+        fn borrow_left(
+            parent: &mut Page<K, V>,
+            idx: usize,
+            left: &mut Page<K, V>,
+            page: &mut Page<K, V>,
+        ) {
+            let sep = idx - 1;
+            match page.ptype {
+                PageType::Leaf => {
+                    page.keys.insert(0, left.keys.pop().unwrap());
+                    page.vals.insert(0, left.vals.pop().unwrap());
+                    page.versions.insert(0, left.versions.pop().unwrap());
+                    page.overflow.insert(0, left.overflow.pop().unwrap());
+                    if let Some(last) = left.keys.last() {
+                        parent.keys[sep] = separator(last, &page.keys[0]);
+                    }
+                }
+                _ => {
+                    let key = std::mem::replace(&mut parent.keys[sep], left.keys.pop().unwrap());
+                    page.keys.insert(0, key);
+                    page.children.insert(0, left.children.pop().unwrap());
+                    page.counts.insert(0, left.counts.pop().unwrap());
+                }
+            }
+            parent.counts[sep] = left.count();
+            parent.counts[idx] = page.count();
+        }
+
+        // Move the first entry of right to the end of page, its left sibling
+        // at index idx of parent.
+        fn borrow_right(
+            parent: &mut Page<K, V>,
+            idx: usize,
+            page: &mut Page<K, V>,
+            right: &mut Page<K, V>,
+        ) {
+            match page.ptype {
+                PageType::Leaf => {
+                    page.keys.push(right.keys.remove(0));
+                    page.vals.push(right.vals.remove(0));
+                    page.versions.push(right.versions.remove(0));
+                    page.overflow.push(right.overflow.remove(0));
+                    // right is only left empty until the entry is given back
+                    if let Some(first) = right.keys.first() {
+                        parent.keys[idx] = separator(page.keys.last().unwrap(), first);
+                    }
+                }
+                _ => {
+                    let key = std::mem::replace(&mut parent.keys[idx], right.keys.remove(0));
+                    page.keys.push(key);
+                    page.children.push(right.children.remove(0));
+                    page.counts.push(right.counts.remove(0));
+                }
+            }
+            parent.counts[idx] = page.count();
+            parent.counts[idx + 1] = right.count();
+        }
+
+        // Point the left sibling link of the leaf after page back at page.
+        fn relink_right(&mut self, page: &Page<K, V>) -> Result<(), BTreeError> {
+            if let (PageType::Leaf, Some(id)) = (page.ptype, page.sibling) {
+                let mut next = self.pool.take(id)?;
+                next.left_sibling = Some(page.id);
+                self.pool.put(next)?;
+            }
+            Ok(())
+        }
+
+        // Append right, the child after left at index idx of parent, to left
+        // and remove it from parent. The caller frees right's page.
+        fn merge(parent: &mut Page<K, V>, idx: usize, left: &mut Page<K, V>, right: Page<K, V>) {
+            let sep = parent.keys.remove(idx);
+            parent.children.remove(idx + 1);
+            parent.counts[idx] += parent.counts.remove(idx + 1);
+            match left.ptype {
+                PageType::Leaf => {
+                    left.sibling = right.sibling;
+                    left.versions.extend(right.versions);
+                    left.overflow.extend(right.overflow);
+                    left.vals.extend(right.vals);
+                }
+                _ => {
+                    left.keys.push(sep);
+                    left.children.extend(right.children);
+                    left.counts.extend(right.counts);
+                }
+            }
+            left.keys.extend(right.keys);
+        }
+
+        // traverse page IDs in level order
+        fn traverse(&self) -> Result<Vec<Vec<u32>>, BTreeError> {
+            let mut lvl = 0;
+            let mut ids = vec![vec![self.root_id]];
+            let mut q = VecDeque::from([(0, self.root_id)]);
+            let max_loop = self.next_id;
+            for _ in 0..=max_loop {
+                match q.pop_front() {
+                    Some((l, id)) => {
+                        if l >= lvl {
+                            lvl += 1;
+                            ids.push(vec![]);
+                        }
+                        let page = self.pool.fetch(id)?;
+                        if page.ptype == PageType::Leaf {
+                            continue;
+                        }
+                        for cid in page.children.iter() {
+                            ids[lvl].push(*cid);
+                            q.push_back((l + 1, *cid));
+                        }
+                    }
+                    None => {
+                        break;
+                    }
+                }
+            }
+            ids.pop();
+            Ok(ids)
+        }
+
+        #[cfg(test)]
+        pub(crate) fn root_id(&self) -> u32 {
+            self.root_id
+        }
+
+        // Check the structure of the tree: key order and separators, page
+        // occupancy, subtree counts, leaf depth and the sibling chain.
+        #[cfg(test)]
+        pub(crate) fn assert_valid(&self) {
+            let mut leaves = vec![];
+            self.check_page(self.root_id, 0, None, None, &mut leaves);
+            if self.shadow.is_some() {
+                // leaves of copy-on-write trees aren't linked
+                for &id in leaves.iter() {
+                    let page = self.pool.fetch(id).unwrap();
+                    assert_eq!((page.sibling, page.left_sibling), (None, None));
+                }
+            } else {
+                for pair in leaves.windows(2) {
+                    let page = self.pool.fetch(pair[0]).unwrap();
+                    assert_eq!(page.sibling, Some(pair[1]), "broken sibling link");
+                    let next = self.pool.fetch(pair[1]).unwrap();
+                    assert_eq!(next.left_sibling, Some(pair[0]), "broken left sibling link");
+                }
+                let first = self.pool.fetch(leaves[0]).unwrap();
+                assert_eq!(first.left_sibling, None);
+                let last = self.pool.fetch(*leaves.last().unwrap()).unwrap();
+                assert_eq!(last.sibling, None);
+            }
+            if self.is_unique {
+                // a key may have several versions, but only one live entry
+                let mut cursor = self.cursor();
+                let mut valid = cursor.first().unwrap();
+                let mut prev: Option<K> = None;
+                while valid {
+                    let key = cursor.key().unwrap();
+                    assert!(prev.as_ref() != Some(key), "duplicate live key");
+                    prev = Some(key.clone());
+                    valid = cursor.next().unwrap();
+                }
+            }
+        }
+
+        // Returns the number of live entries under the page.
+        #[cfg(test)]
+        fn check_page(
+            &self,
+            id: u32,
+            level: usize,
+            lo: Option<&K>,
+            hi: Option<&K>,
+            leaves: &mut Vec<u32>,
+        ) -> usize {
+            let page = self.pool.fetch(id).unwrap();
+            let fill = self.page_fill(&page);
+            assert!(fill <= 1.0, "page {} overfull", id);
+            assert!(page.byte_size() <= PAGE_SIZE);
+            if id != self.root_id {
+                // pages of wide entries may be left below MIN_FILL when they
+                // don't fit together with a sibling
+                let quarter = 4 * page.byte_size() >= PAGE_SIZE;
+                assert!(fill >= MIN_FILL || quarter, "page {} underfull", id);
+            }
+            assert!(
+                page.keys.windows(2).all(|w| w[0] <= w[1]),
+                "page {} unsorted",
+                id
+            );
+            for k in page.keys.iter() {
+                // duplicates, or the versions of a key, may continue past a
+                // separator
+                assert!(lo.is_none_or(|lo| k >= lo));
+                assert!(hi.is_none_or(|hi| k <= hi));
+            }
+            if page.ptype == PageType::Leaf {
+                assert_eq!(level, self.depth, "leaf {} at wrong depth", id);
+                leaves.push(id);
+                return page.count();
+            }
+            assert_eq!(page.children.len(), page.keys.len() + 1);
+            assert_eq!(page.counts.len(), page.children.len());
+            for (i, &cid) in page.children.iter().enumerate() {
+                let c_lo = if i == 0 { lo } else { Some(&page.keys[i - 1]) };
+                let c_hi = page.keys.get(i).or(hi);
+                let count = self.check_page(cid, level + 1, c_lo, c_hi, leaves);
+                assert_eq!(
+                    page.counts[i], count,
+                    "wrong count of child {} of {}",
+                    i, id
+                );
+            }
+            page.count()
+        }
+    }
+
+    // ------------------- Cursor -------------------
+
+    /// A position in a BTree, used to walk its entries in either direction
+    /// without collecting them. Soft deleted entries are skipped, and a
+    /// cursor reading a snapshot only stops at the entries it sees.
+    /// A new cursor isn't positioned on an entry until one of seek, first
+    /// or last is called.
+    pub struct Cursor<'a, K: Key, V: Val> {
+        tree: &'a BTree<K, V>,
+        snapshot: Option<Snapshot>,
+        // interior pages from the root down to the leaf, with child indices;
+        // only stepped along for copy-on-write trees, whose leaves aren't
+        // linked
+        path: Vec<(u32, usize)>,
+        leaf: Option<Rc<Page<K, V>>>,
+        idx: usize,
+    }
+
+    impl<K: Key + 'static, V: Val + 'static> BTree<K, V> {
+        pub fn cursor(&self) -> Cursor<'_, K, V> {
+            Cursor {
+                tree: self,
+                snapshot: None,
+                path: vec![],
+                leaf: None,
+                idx: 0,
+            }
+        }
+
+        // A cursor over the tree as the snapshot sees it, which must not have
+        // been released.
+        #[allow(dead_code)]
+        pub fn cursor_at(&self, snap: &Snapshot) -> Cursor<'_, K, V> {
+            Cursor {
+                snapshot: Some(*snap),
+                ..self.cursor()
+            }
+        }
+    }
+
+    impl<'a, K: Key + 'static, V: Val + 'static> Cursor<'a, K, V> {
+        // Position the cursor at the first entry with a key >= key.
+        pub fn seek(&mut self, key: &K) -> Result<bool, BTreeError> {
+            let (path, id) = self.tree.find_path(key)?;
+            let leaf = self.tree.pool.fetch(id)?;
+            self.idx = leaf.find(key);
+            self.path = path;
+            self.leaf = Some(leaf);
+            self.skip_forward()
+        }
+
+        // Position the cursor at the last entry with a key <= key, to iterate
+        // backwards from there.
+        #[allow(dead_code)]
+        pub fn seek_rev(&mut self, key: &K) -> Result<bool, BTreeError> {
+            let mut valid = self.seek(key)?;
+            // step past any duplicates of key
+            while valid && self.key() == Some(key) {
+                valid = self.next()?;
+            }
+            if valid {
+                self.prev()
+            } else {
+                self.last()
+            }
+        }
+
+        // Position the cursor at the entry at position i in key order,
+        // counting from 0, by descending through the subtree counts. The
+        // counts are of the latest state of the tree, whatever the cursor
+        // reads.
+        pub fn seek_nth(&mut self, i: usize) -> Result<bool, BTreeError> {
+            let (path, id, idx) = self.tree.find_nth(i)?;
+            self.path = path;
+            self.idx = idx;
+            self.leaf = Some(self.tree.pool.fetch(id)?);
+            self.skip_forward()
+        }
+
+        // Position the cursor at the first entry of the tree.
+        #[allow(dead_code)]
+        pub fn first(&mut self) -> Result<bool, BTreeError> {
+            self.descend(false)?;
+            self.idx = 0;
+            self.skip_forward()
+        }
+
+        // Position the cursor at the last entry of the tree.
+        #[allow(dead_code)]
+        pub fn last(&mut self) -> Result<bool, BTreeError> {
+            self.descend(true)?;
+            self.skip_backward()
+        }
+
+        // Step to the following entry.
+        // Returns false when there is none, which leaves the cursor unpositioned.
+        pub fn next(&mut self) -> Result<bool, BTreeError> {
+            if self.leaf.is_none() {
+                return Ok(false);
+            }
+            self.idx += 1;
+            self.skip_forward()
+        }
+
+        // Step to the preceding entry.
+        // Returns false when there is none, which leaves the cursor unpositioned.
+        pub fn prev(&mut self) -> Result<bool, BTreeError> {
+            if self.leaf.is_none() {
+                return Ok(false);
+            }
+            self.skip_backward()
+        }
+
+        pub fn key(&self) -> Option<&K> {
+            self.leaf.as_ref().map(|leaf| &leaf.keys[self.idx])
+        }
+
+        pub fn val(&self) -> Option<&V> {
+            self.leaf.as_ref().map(|leaf| &leaf.vals[self.idx])
+        }
+
+        // Walk down the leftmost or rightmost edge of the tree. The cursor is
+        // left at the end of the rightmost leaf.
+        fn descend(&mut self, rightmost: bool) -> Result<(), BTreeError> {
+            self.path.clear();
+            let mut id = self.tree.root_id;
+            for _ in 0..self.tree.depth {
+                let page = self.tree.pool.fetch(id)?;
+                let idx = if rightmost {
+                    page.children.len() - 1
+                } else {
+                    0
+                };
+                self.path.push((id, idx));
+                id = page.children[idx];
+            }
+            let leaf = self.tree.pool.fetch(id)?;
+            self.idx = leaf.keys.len();
+            self.leaf = Some(leaf);
+            Ok(())
+        }
+
+        // Whether the entry with the version is read by the cursor.
+        fn sees(&self, version: &Version) -> bool {
+            match &self.snapshot {
+                Some(snap) => snap.sees(version),
+                None => version.is_live(),
+            }
+        }
+
+        // Move forward from idx, inclusive, to the first live entry.
+        fn skip_forward(&mut self) -> Result<bool, BTreeError> {
+            while let Some(leaf) = &self.leaf {
+                if self.idx < leaf.keys.len() {
+                    if self.sees(&leaf.versions[self.idx]) {
+                        return Ok(true);
+                    }
+                    self.idx += 1;
+                    continue;
+                }
+                self.leaf = match self.tree.leaf_after(&mut self.path, leaf)? {
+                    Some(id) => Some(self.tree.pool.fetch(id)?),
+                    None => None,
+                };
+                self.idx = 0;
+            }
+            Ok(false)
+        }
+
+        // Move backward from idx, exclusive, to the first live entry.
+        fn skip_backward(&mut self) -> Result<bool, BTreeError> {
+            while let Some(leaf) = &self.leaf {
+                if self.idx > 0 {
+                    self.idx -= 1;
+                    if self.sees(&leaf.versions[self.idx]) {
+                        return Ok(true);
+                    }
+                    continue;
+                }
+                self.leaf = match self.tree.leaf_before(&mut self.path, leaf)? {
+                    Some(id) => Some(self.tree.pool.fetch(id)?),
+                    None => None,
+                };
+                self.idx = self.leaf.as_ref().map_or(0, |leaf| leaf.keys.len());
+            }
+            Ok(false)
+        }
+    }
+}
+
+pub mod checksum {
+    // CRC-32 (IEEE 802.3) lookup table for the reflected polynomial.
+    const fn make_table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut j = 0;
+            while j < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB88320
+                } else {
+                    crc >> 1
+                };
+                j += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    }
+
+    static CRC_TABLE: [u32; 256] = make_table();
+
+    // Crc32 computes a checksum over several byte slices.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Crc32(u32);
+
+    impl Crc32 {
+        pub fn new() -> Self {
+            Crc32(!0)
+        }
+
+        pub fn update(&mut self, bytes: &[u8]) {
+            for &b in bytes.iter() {
+                self.0 = CRC_TABLE[((self.0 ^ b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+            }
+        }
+
+        pub fn finish(&self) -> u32 {
+            !self.0
+        }
+    }
+
+    impl Default for Crc32 {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    pub fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(bytes);
+        crc.finish()
+    }
+}
+
+pub mod wal {
+    use super::btree::{PagerError, PAGE_SIZE};
+    use super::checksum::Crc32;
+    use std::collections::HashMap;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    pub const WAL_MAGIC: [u8; 8] = *b"bokewal\0";
+    pub const WAL_VERSION: u32 = 1;
+    const WAL_HEADER_LEN: u64 = 12;
+    const FRAME_HEADER_LEN: usize = 13;
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    enum FrameType {
+        Page = 0,
+        Commit = 1,
+    }
+
+    /// Wal is a write-ahead log of page images.
+    /// Pages are appended to the log instead of the database file and become
+    /// visible after a crash only once a commit frame follows them. Committed
+    /// pages are copied into the database file by a checkpoint.
+    ///
+    /// The log starts with the magic and version, followed by frames:
+    ///
+    ///  0            1-5       5-9      9-13
+    /// +------------+---------+--------+-------+---------+
+    /// | frame type | page id | length | crc32 | payload |
+    /// +------------+---------+--------+-------+---------+
+    ///
+    /// The checksum covers the frame header before it and the payload.
+    #[derive(Debug)]
+    pub struct Wal {
+        file: File,
+        len: u64,
+        committed: HashMap<u32, u64>, // page ID -> offset of latest committed frame
+        pending: HashMap<u32, u64>,   // page ID -> offset of latest uncommitted frame
+        n_frames: usize,              // page frames since the last checkpoint
+    }
+
+    impl Wal {
+        // Open the log at path, creating it if it doesn't exist.
+        // Frames after the last intact commit frame are discarded.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Wal, PagerError> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            let mut wal = Wal {
+                file,
+                len: WAL_HEADER_LEN,
+                committed: HashMap::new(),
+                pending: HashMap::new(),
+                n_frames: 0,
+            };
+            if !wal.recover()? {
+                wal.reset()?;
+            }
+            Ok(wal)
+        }
+
+        // Scan the log and index committed frames. Returns false if the log
+        // header is missing or invalid.
+        fn recover(&mut self) -> Result<bool, PagerError> {
+            let file_len = self.file.metadata()?.len();
+            let mut reader = BufReader::new(&self.file);
+            reader.seek(SeekFrom::Start(0))?;
+            let mut header = [0; WAL_HEADER_LEN as usize];
+            if reader.read_exact(&mut header).is_err()
+                || header[0..8] != WAL_MAGIC
+                || header[8..12] != WAL_VERSION.to_le_bytes()
+            {
+                return Ok(false);
+            }
+
+            let mut offset = WAL_HEADER_LEN;
+            let mut commit_len = WAL_HEADER_LEN;
+            let mut n_frames = 0;
+            let mut frame_header = [0; FRAME_HEADER_LEN];
+            let mut payload = vec![];
+            loop {
+                if reader.read_exact(&mut frame_header).is_err() {
+                    break;
+                }
+                let id = u32::from_le_bytes(frame_header[1..5].try_into().unwrap());
+                let len = u32::from_le_bytes(frame_header[5..9].try_into().unwrap()) as usize;
+                let crc = u32::from_le_bytes(frame_header[9..13].try_into().unwrap());
+                let frame_len = (FRAME_HEADER_LEN + len) as u64;
+                if len > PAGE_SIZE || offset + frame_len > file_len {
+                    break;
+                }
+                payload.resize(len, 0);
+                if reader.read_exact(&mut payload).is_err()
+                    || Self::checksum(&frame_header, &payload) != crc
+                {
+                    break;
+                }
+                if frame_header[0] == FrameType::Page as u8 {
+                    self.pending.insert(id, offset);
+                    n_frames += 1;
+                } else if frame_header[0] == FrameType::Commit as u8 {
+                    self.committed.extend(self.pending.drain());
+                    commit_len = offset + frame_len;
+                    self.n_frames = n_frames;
+                } else {
+                    break;
+                }
+                offset += frame_len;
+            }
+
+            // drop uncommitted and torn frames
+            self.pending.clear();
+            self.file.set_len(commit_len)?;
+            self.len = commit_len;
+            Ok(true)
+        }
+
+        fn checksum(frame_header: &[u8], payload: &[u8]) -> u32 {
+            let mut crc = Crc32::new();
+            crc.update(&frame_header[0..9]);
+            crc.update(payload);
+            crc.finish()
+        }
+
+        fn append_frame(
+            &mut self,
+            ftype: FrameType,
+            id: u32,
+            payload: &[u8],
+        ) -> Result<u64, PagerError> {
+            let offset = self.len;
+            let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+            bytes.push(ftype as u8);
+            bytes.extend(id.to_le_bytes());
+            bytes.extend(u32::try_from(payload.len()).unwrap().to_le_bytes());
+            bytes.extend(Self::checksum(&bytes, payload).to_le_bytes());
+            bytes.extend_from_slice(payload);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&bytes)?;
+            self.len += bytes.len() as u64;
+            Ok(offset)
+        }
+
+        // Log a new image of the page. It isn't durable until commit.
+        pub fn append(&mut self, id: u32, payload: &[u8]) -> Result<(), PagerError> {
+            let offset = self.append_frame(FrameType::Page, id, payload)?;
+            self.pending.insert(id, offset);
+            self.n_frames += 1;
+            Ok(())
+        }
+
+        // Make all logged pages durable.
+        pub fn commit(&mut self) -> Result<(), PagerError> {
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+            self.append_frame(FrameType::Commit, 0, &[])?;
+            self.file.sync_data()?;
+            self.committed.extend(self.pending.drain());
+            Ok(())
+        }
+
+        // Read the latest logged image of the page, if any.
+        pub fn read(&self, id: u32) -> Result<Option<Vec<u8>>, PagerError> {
+            let offset = match self.pending.get(&id).or_else(|| self.committed.get(&id)) {
+                Some(&offset) => offset,
+                None => return Ok(None),
+            };
+            let mut file = &self.file;
+            let mut frame_header = [0; FRAME_HEADER_LEN];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut frame_header)?;
+            let len = u32::from_le_bytes(frame_header[5..9].try_into().unwrap()) as usize;
+            let mut payload = vec![0; len];
+            file.read_exact(&mut payload)?;
+            Ok(Some(payload))
+        }
+
+        // IDs of pages with committed images, in ascending order.
+        pub fn committed_pages(&self) -> Vec<u32> {
+            let mut ids: Vec<u32> = self.committed.keys().copied().collect();
+            ids.sort();
+            ids
+        }
+
+        pub fn has_pending(&self) -> bool {
+            !self.pending.is_empty()
+        }
+
+        // Number of page frames in the log.
+        pub fn frames(&self) -> usize {
+            self.n_frames
+        }
+
+        // Empty the log. Only safe once committed pages are in the database file.
+        pub fn reset(&mut self) -> Result<(), PagerError> {
+            let mut header = WAL_MAGIC.to_vec();
+            header.extend(WAL_VERSION.to_le_bytes());
+            self.file.set_len(0)?;
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.write_all(&header)?;
+            self.file.sync_data()?;
+            self.len = WAL_HEADER_LEN;
+            self.committed.clear();
+            self.pending.clear();
+            self.n_frames = 0;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::btree::*;
+    use super::checksum::crc32;
+    use crate::types::values::*;
+    use rand::prelude::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    // A fresh database path in the system temp directory.
+    fn tmp_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bokedb-{}-{}.db", name, std::process::id()));
+        remove_db(&path);
+        path
+    }
+
+    fn wal_path(path: &Path) -> PathBuf {
+        let mut wal = path.as_os_str().to_owned();
+        wal.push("-wal");
+        PathBuf::from(wal)
+    }
+
+    fn remove_db(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(wal_path(path));
+    }
+
+    #[test]
+    fn test_insert_no_split() {
+        let mut bt: BTree<i32, i32> = BTree::new(27, false);
+        let kvs = [(5, 50), (6, 60), (7, -70), (7, 70), (8, 80)];
+        for (k, v) in kvs.into_iter() {
+            let err = bt.insert(k, v);
+            assert!(err.is_ok());
+        }
+        assert_eq!(bt.find(&5).unwrap(), Some(50));
+        assert_eq!(bt.find(&6).unwrap(), Some(60));
+        assert_eq!(bt.find(&8).unwrap(), Some(80));
+    }
+
+    #[test]
+    fn test_insert_split() {
+        let mut bt: BTree<i32, i32> = BTree::new(3, true);
+        let kvs = [(5, 55), (6, 66), (7, 77), (8, 88), (9, 99), (10, 100)];
+        for (k, v) in kvs.into_iter() {
+            let err = bt.insert(k, v);
+            assert!(err.is_ok());
+        }
+
+        assert_eq!(bt.find(&5).unwrap(), Some(55));
+        assert_eq!(bt.find(&6).unwrap(), Some(66));
+        assert_eq!(bt.find(&7).unwrap(), Some(77));
+        assert_eq!(bt.find(&9).unwrap(), Some(99));
+        assert_eq!(bt.find(&8).unwrap(), Some(88));
+        assert_eq!(bt.find(&666).unwrap(), None);
+    }
+
+    #[test]
+    fn test_insert_rand() {
+        let mut rng = rand::thread_rng();
+        let sizes = [33, 101, 179, 213, 303];
+        for size in sizes.into_iter() {
+            println!("size={}", size);
+            let mut bt: BTree<i32, i32> = BTree::new(size, true);
+            let mut keys: Vec<i32> = (0..50000).map(|_| rng.gen::<i32>()).collect();
+            keys.sort();
+            keys.dedup();
+            let mut vals = vec![];
+            for &k in keys.iter() {
+                let val: i32 = rng.gen();
+                vals.push(val);
+                let err = bt.insert(k, val);
+                assert!(err.is_ok());
+            }
+            for (k, v) in keys.iter().zip(vals) {
+                assert_eq!(bt.find(k).unwrap(), Some(v));
+            }
+        }
+    }
+
+    #[test]
+    fn test_delete_rand() {
+        let sizes = [71, 155, 191, 211, 301];
+        for size in sizes.into_iter() {
+            println!("size={}", size);
+            let mut rng = rand::thread_rng();
+            let mut bt: BTree<i32, i32> = BTree::new(size, true);
+            let n = 50000;
+            let mut keys: Vec<i32> = (0..n).map(|_| rng.gen::<i32>()).collect();
+            keys.sort();
+            keys.dedup();
+            let mut vals = vec![];
+            for &k in keys.iter() {
+                let val: i32 = rng.gen();
+                vals.push(val);
+                let err = bt.insert(k, val);
+                assert!(err.is_ok());
+            }
+            for k in keys[n / 2..].iter() {
+                let err = bt.delete(k);
+                assert!(err.is_ok());
+            }
+            for (i, k) in keys[0..n / 2].iter().enumerate() {
+                assert_eq!(bt.find(k).unwrap(), Some(vals[i]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_delete_one() {
+        let mut bt: BTree<i32, i32> = BTree::new(3, true);
+        let kvs = [(3, 333), (5, 55), (6, 66), (7, 77), (9, 99), (10, 100)];
+        for (k, v) in kvs.into_iter() {
+            let err = bt.insert(k, v);
+            assert!(err.is_ok());
+        }
+
+        assert_eq!(bt.find(&5).unwrap(), Some(55));
+        let err = bt.delete(&5);
+        assert!(err.is_ok());
+        assert_eq!(err.unwrap(), 1_usize);
+        assert_eq!(bt.find(&5).unwrap(), None);
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        let mut bt: BTree<i32, i32> = BTree::new(5, true);
+        assert!(bt.insert(5, 55).is_ok());
+        let err = bt.insert(5, 555);
+        assert!(matches!(err, Err(BTreeError::DuplicateKey)));
+
+        assert!(bt.delete(&5).is_ok());
+        assert!(bt.insert(5, 555).is_ok());
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut bt: BTree<i32, i32> = BTree::new(3, false);
+        let kvs = [
+            (5, 55),
+            (6, 66),
+            (7, 77),
+            (9, 99),
+            (9, 999),
+            (9, 9999),
+            (10, 100),
+            (3, 333),
+        ];
+        for (k, v) in kvs.into_iter() {
+            let err = bt.insert(k, v);
+            assert!(err.is_ok());
+        }
+        bt.set_soft_delete(true);
+        assert!(bt.delete(&5).is_ok());
+        assert!(bt.delete(&9).is_ok());
+        assert!(bt.delete(&7).is_ok());
+
+        assert!(bt.rebuild().unwrap() > 0);
+        bt.assert_valid();
+
+        assert_eq!(bt.find(&9).unwrap(), None);
+        assert_eq!(bt.find(&5).unwrap(), None);
+        assert_eq!(bt.find(&7).unwrap(), None);
+        assert_eq!(bt.find(&10).unwrap(), Some(100));
+        assert_eq!(bt.find(&3).unwrap(), Some(333));
+        assert_eq!(bt.find(&6).unwrap(), Some(66));
+    }
+
+    #[test]
+    fn test_rebuild_dense() {
+        for b in [3, 5, 7, 33] {
+            for n in [0, 1, 2, b, 1000] {
+                let mut bt: BTree<i32, i32> = BTree::new(b, true);
+                bt.set_soft_delete(true);
+                for k in 0..2 * n as i32 {
+                    bt.insert(k, k).unwrap();
+                }
+                for k in (0..2 * n as i32).step_by(2) {
+                    bt.delete(&k).unwrap();
+                }
+                bt.rebuild().unwrap();
+                bt.assert_valid();
+                let kvs = bt.find_range(&i32::MIN, &i32::MAX).unwrap();
+                let expected: Vec<(i32, i32)> =
+                    (0..n as i32).map(|i| (2 * i + 1, 2 * i + 1)).collect();
+                assert_eq!(kvs, expected);
+                // rebuilt pages come from the free list
+                let header = bt.header();
+                let n_pages: u32 = (header.next_id - 1) - header.free_count;
+                assert!(n_pages as usize <= 1 + 2 * n / (b - 1) + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_range() {
+        let mut bt: BTree<i32, i32> = BTree::new(33, true);
+        for i in (0..10000).step_by(3) {
+            let err = bt.insert(i, 3 * i);
+            assert!(err.is_ok());
+        }
+
+        let min = 51;
+        let max = 300;
+        let kvs = bt.find_range(&min, &max).unwrap();
+        assert_eq!(51, kvs.first().unwrap().0);
+        assert_eq!(300, kvs.last().unwrap().0);
+        assert_eq!(kvs.len(), ((max - min) / 3 + 1) as usize);
+
+        for (k, v) in kvs.into_iter() {
+            assert!(k >= 51);
+            assert!(300 >= k);
+            assert_eq!(v, 3 * k);
+        }
+    }
+
+    #[test]
+    fn test_pack_bits() {
+        // 01101001 11000001 10110100 001
+        let bits = vec![
+            false, true, true, false, true, false, false, true, true, true, false, false, false,
+            false, false, true, true, false, true, true, false, true, false, false, false, false,
+            true,
+        ];
+        let len = bits.len();
+
+        let bytes = pack_bits(&bits);
+        assert_eq!(bytes.len(), 4);
+
+        let unpacked = unpack_bits(len, &bytes[..]);
+        for i in 0..bits.len() {
+            assert_eq!(bits[i], unpacked[i]);
+        }
+    }
+
+    #[test]
+    fn test_file_reopen() {
+        let path = tmp_db("reopen");
+        let keys: Vec<i32> = (0..2000).map(|i| (i * 7919) % 2000).collect();
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 33, true).unwrap();
+            for &k in keys.iter() {
+                assert!(bt.insert(k, -k).is_ok());
+            }
+            assert!(bt.delete(&10).is_ok());
+        }
+
+        let mut bt: BTree<i32, i32> = BTree::open(&path, 33, true).unwrap();
+        for &k in keys.iter().filter(|&&k| k != 10) {
+            assert_eq!(bt.find(&k).unwrap(), Some(-k));
+        }
+        assert_eq!(bt.find(&10).unwrap(), None);
+        assert_eq!(bt.find_range(&0, &1999).unwrap().len(), 1999);
+
+        // the reopened tree keeps growing from where it left off
+        for k in 2000..3000 {
+            assert!(bt.insert(k, -k).is_ok());
+        }
+        assert_eq!(bt.find(&2999).unwrap(), Some(-2999));
+        assert!(matches!(bt.insert(5, 5), Err(BTreeError::DuplicateKey)));
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_file_rows() {
+        let path = tmp_db("rows");
+        let row = |i: i32| {
+            vec![
+                Value::VarChar(VarChar::new(&format!("user{}", i))),
+                Value::VarChar(VarChar::new(&format!("user{}@example.com", i))),
+            ]
+        };
+        {
+            let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 101, true).unwrap();
+            for i in 0..500 {
+                assert!(bt.insert(i, row(i)).is_ok());
+            }
+        }
+        let bt: BTree<i32, Vec<Value>> = BTree::open(&path, 101, true).unwrap();
+        for i in 0..500 {
+            assert_eq!(bt.find(&i).unwrap(), Some(row(i)));
+        }
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_header_reopen() {
+        let path = tmp_db("header");
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, false).unwrap();
+            for k in 0..100 {
+                assert!(bt.insert(k % 10, k).is_ok());
+            }
+        }
+        // b and is_unique come from the header, not the arguments
+        let mut bt: BTree<i32, i32> = BTree::open(&path, 101, true).unwrap();
+        let header = bt.header();
+        assert_eq!(header.b, 5);
+        assert!(!header.is_unique);
+        assert!(header.depth > 0);
+        assert!(bt.insert(3, 3).is_ok());
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_header_validation() {
+        let path = tmp_db("foreign");
+        fs::write(&path, vec![0x42; PAGE_SIZE]).unwrap();
+        let res: Result<BTree<i32, i32>, _> = BTree::open(&path, 5, true);
+        assert!(matches!(res, Err(BTreeError::Pager(PagerError::BadMagic))));
+
+        // bump the format version of a valid file
+        remove_db(&path);
+        drop(BTree::<i32, i32>::open(&path, 5, true).unwrap());
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        let res: Result<BTree<i32, i32>, _> = BTree::open(&path, 5, true);
+        assert!(matches!(
+            res,
+            Err(BTreeError::Pager(PagerError::UnsupportedVersion(v))) if v == FORMAT_VERSION + 1
+        ));
+
+        // key size written by a tree with a different key type
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes[16..20].copy_from_slice(&8_u32.to_le_bytes());
+        let checksum = crc32(&bytes[0..69]);
+        bytes[69..73].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        let res: Result<BTree<i32, i32>, _> = BTree::open(&path, 5, true);
+        assert!(matches!(
+            res,
+            Err(BTreeError::Pager(PagerError::KeySizeMismatch {
+                found: 8,
+                expected: 4
+            }))
+        ));
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_pool_cache() {
+        let mut bt: BTree<i32, i32> = BTree::new(5, true);
+        for k in 0..1000 {
+            assert!(bt.insert(k, k).is_ok());
+        }
+        // with everything cached, lookups never miss
+        let before = bt.pool_stats();
+        for k in 0..1000 {
+            assert_eq!(bt.find(&k).unwrap(), Some(k));
+        }
+        let after = bt.pool_stats();
+        assert_eq!(after.misses, before.misses);
+        assert!(after.hits > before.hits);
+        assert_eq!(after.evictions, 0);
+    }
+
+    #[test]
+    fn test_pool_eviction() {
+        let path = tmp_db("pool");
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            bt.set_pool_capacity(8).unwrap();
+            for k in (0..1000).rev() {
+                assert!(bt.insert(k, 2 * k).is_ok());
+            }
+            for k in 0..1000 {
+                assert_eq!(bt.find(&k).unwrap(), Some(2 * k));
+            }
+            let stats = bt.pool_stats();
+            assert!(stats.evictions > 0);
+            assert!(stats.writes > 0);
+            assert!(stats.misses > 0);
+            bt.flush().unwrap();
+        }
+        let bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        assert_eq!(bt.find_range(&0, &1000).unwrap().len(), 1000);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_wal_discard_uncommitted() {
+        let path = tmp_db("wal-uncommitted");
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            for k in 0..100 {
+                assert!(bt.insert(k, k).is_ok());
+            }
+            bt.flush().unwrap();
+            // evictions log pages that are never committed
+            bt.set_pool_capacity(4).unwrap();
+            for k in 100..1000 {
+                assert!(bt.insert(k, k).is_ok());
+            }
+            std::mem::forget(bt);
+        }
+        let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        assert_eq!(bt.find_range(&0, &1000).unwrap().len(), 100);
+        assert_eq!(bt.find(&99).unwrap(), Some(99));
+        assert_eq!(bt.find(&500).unwrap(), None);
+
+        assert!(bt.insert(500, 5).is_ok());
+        bt.checkpoint().unwrap();
+        assert_eq!(fs::metadata(wal_path(&path)).unwrap().len(), 12);
+        drop(bt);
+        let bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        assert_eq!(bt.find(&500).unwrap(), Some(5));
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_wal_torn_commit() {
+        let path = tmp_db("wal-torn");
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            for k in 0..100 {
+                assert!(bt.insert(k, k).is_ok());
+            }
+            bt.flush().unwrap();
+            for k in 100..200 {
+                assert!(bt.insert(k, k).is_ok());
+            }
+            bt.flush().unwrap();
+            std::mem::forget(bt);
+        }
+        // tear the last commit frame
+        let mut bytes = fs::read(wal_path(&path)).unwrap();
+        let n = bytes.len();
+        bytes[n - 1] ^= 0xff;
+        fs::write(wal_path(&path), bytes).unwrap();
+
+        let bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        assert_eq!(bt.find_range(&0, &200).unwrap().len(), 100);
+        assert_eq!(bt.find(&150).unwrap(), None);
+        drop(bt);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_page_checksum() {
+        let path = tmp_db("checksum");
+        drop(BTree::<i32, i32>::open(&path, 5, true).unwrap());
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            for k in 0..100 {
+                assert!(bt.insert(k, k).is_ok());
+            }
+        }
+        let clean = fs::read(&path).unwrap();
+        let root_id = {
+            let bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            bt.header().root_id
+        };
+
+        // flip a bit in the root page
+        let mut bytes = clean.clone();
+        bytes[root_id as usize * PAGE_SIZE + 100] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        let bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        let res = bt.find(&1);
+        assert!(matches!(res, Err(BTreeError::Pager(PagerError::Checksum(id))) if id == root_id));
+        std::mem::forget(bt);
+
+        // torn write: only the first 4 KiB of the root page made it to disk,
+        // the rest is left over from an older write
+        let mut bytes = clean.clone();
+        let start = root_id as usize * PAGE_SIZE + 4096;
+        bytes[start..(start + PAGE_SIZE - 4096)].fill(0xab);
+        fs::write(&path, &bytes).unwrap();
+        let bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        assert!(bt.find(&1).is_err());
+        std::mem::forget(bt);
+
+        // corrupt header
+        let mut bytes = clean;
+        bytes[30] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        let res: Result<BTree<i32, i32>, _> = BTree::open(&path, 5, true);
+        assert!(matches!(
+            res,
+            Err(BTreeError::Pager(PagerError::Checksum(0)))
+        ));
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_free_list() {
+        let path = tmp_db("free-list");
+        let freed = {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            let ids: Vec<u32> = (0..4).map(|_| bt.alloc_page().unwrap()).collect();
+            for &id in ids.iter() {
+                bt.free_page(id).unwrap();
+            }
+            assert_eq!(bt.header().free_count, 4);
+            ids
+        };
+
+        // the free list survives reopening and is used before growing the file
+        let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        let header = bt.header();
+        assert_eq!(header.free_count, 4);
+        assert_eq!(header.free_head, freed.last().copied());
+        let next_id = header.next_id;
+        for k in 0..16 {
+            assert!(bt.insert(k, k).is_ok());
+        }
+        let header = bt.header();
+        assert_eq!(header.free_count, 0);
+        assert_eq!(header.free_head, None);
+        assert_eq!(header.next_id, next_id);
+
+        for k in 16..100 {
+            assert!(bt.insert(k, k).is_ok());
+        }
+        assert!(bt.header().next_id > next_id);
+        for k in 0..100 {
+            assert_eq!(bt.find(&k).unwrap(), Some(k));
+        }
+        drop(bt);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_delete_rebalance() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for b in [3, 5, 11] {
+            let mut bt: BTree<i32, i32> = BTree::new(b, true);
+            let mut keys: Vec<i32> = (0..500).collect();
+            keys.shuffle(&mut rng);
+            for &k in keys.iter() {
+                bt.insert(k, k).unwrap();
+            }
+            let n_pages = bt.header().next_id;
+            assert!(bt.header().depth > 1);
+
+            keys.shuffle(&mut rng);
+            for (i, k) in keys.iter().enumerate() {
+                assert_eq!(bt.delete(k).unwrap(), 1);
+                assert_eq!(bt.find(k).unwrap(), None);
+                if i % 50 == 0 {
+                    bt.assert_valid();
+                    for k in keys[i + 1..].iter() {
+                        assert_eq!(bt.find(k).unwrap(), Some(*k));
+                    }
+                }
+            }
+            bt.assert_valid();
+            let header = bt.header();
+            assert_eq!(header.depth, 0);
+            // every page but the root and header went back on the free list
+            assert_eq!(header.free_count, n_pages - 2);
+            assert!(bt.find_range(&0, &500).unwrap().is_empty());
+            assert!(matches!(bt.delete(&0), Err(BTreeError::KeyNotFound)));
+        }
+    }
+
+    #[test]
+    fn test_delete_duplicates() {
+        let mut bt: BTree<i32, i32> = BTree::new(5, false);
+        for k in 0..40 {
+            for v in 0..3 {
+                bt.insert(k, v).unwrap();
+            }
+        }
+        bt.assert_valid();
+        for k in (0..40).step_by(2) {
+            assert_eq!(bt.delete(&k).unwrap(), 3);
+            bt.assert_valid();
+        }
+        let found = bt.find_range(&0, &40).unwrap();
+        assert_eq!(found.len(), 60);
+        assert!(found.iter().all(|(k, _)| k % 2 == 1));
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        let mut bt: BTree<i32, i32> = BTree::new(5, true);
+        for k in 0..100 {
+            bt.insert(k, k).unwrap();
+        }
+        let header = bt.header();
+        bt.set_soft_delete(true);
+        for k in 0..100 {
+            assert_eq!(bt.delete(&k).unwrap(), 1);
+        }
+        assert!(matches!(bt.delete(&0), Err(BTreeError::KeyNotFound)));
+        assert_eq!(bt.find(&1).unwrap(), None);
+        // tombstones stay in place
+        assert_eq!(bt.header().depth, header.depth);
+        assert_eq!(bt.header().free_count, 0);
+
+        // deleting physically clears out tombstones along the way
+        bt.set_soft_delete(false);
+        for k in 0..100 {
+            bt.insert(k, k).unwrap();
+        }
+        for k in 0..100 {
+            assert_eq!(bt.delete(&k).unwrap(), 1);
+        }
+        bt.assert_valid();
+        assert_eq!(bt.header().depth, 0);
+    }
+
+    // fraction of the slots in the tree's pages that hold an entry
+    fn fill_factor<K: Key + 'static, V: Val + 'static>(
+        bt: &BTree<K, V>,
+        n: usize,
+        b: usize,
+    ) -> f64 {
+        let header = bt.header();
+        let n_pages = (header.next_id - 1 - header.free_count) as usize;
+        n as f64 / (n_pages * (b - 1)) as f64
+    }
+
+    #[test]
+    fn test_overflow_fill() {
+        let b = 33;
+        let n = 10000;
+        let mut bt: BTree<i32, i32> = BTree::new(b, true);
+        for k in 0..n as i32 {
+            bt.insert(k, k).unwrap();
+        }
+        bt.assert_valid();
+        // splitting right away would leave most leaves half full
+        assert!(fill_factor(&bt, n, b) > 0.8);
+        for k in 0..n as i32 {
+            assert_eq!(bt.find(&k).unwrap(), Some(k));
+        }
+
+        let mut bt: BTree<i32, i32> = BTree::new(b, true);
+        for k in (0..n as i32).rev() {
+            bt.insert(k, k).unwrap();
+        }
+        bt.assert_valid();
+        assert!(fill_factor(&bt, n, b) > 0.8);
+
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut keys: Vec<i32> = (0..n as i32).collect();
+        keys.shuffle(&mut rng);
+        let mut bt: BTree<i32, i32> = BTree::new(b, true);
+        for &k in keys.iter() {
+            bt.insert(k, k).unwrap();
+        }
+        bt.assert_valid();
+        assert_eq!(bt.find_range(&0, &(n as i32)).unwrap().len(), n);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        for b in [3, 5, 33] {
+            for n in [0, 1, b - 1, b, b + 1, 1000] {
+                for fill in [0.5, 0.7, 1.0] {
+                    let mut bt: BTree<i32, i32> = BTree::new(b, true);
+                    bt.bulk_load((0..n as i32).map(|k| (k, 2 * k)), fill)
+                        .unwrap();
+                    bt.assert_valid();
+                    let kvs = bt.find_range(&i32::MIN, &i32::MAX).unwrap();
+                    assert_eq!(kvs.len(), n);
+                    assert!(kvs.iter().all(|&(k, v)| v == 2 * k));
+                    // the tree keeps working as usual afterwards
+                    bt.insert(-1, -2).unwrap();
+                    bt.delete(&0).ok();
+                    bt.assert_valid();
+                }
+            }
+        }
+
+        let n = 10000;
+        let mut sparse: BTree<i32, i32> = BTree::new(33, true);
+        sparse
+            .bulk_load((0..n as i32).map(|k| (k, k)), 0.6)
+            .unwrap();
+        let mut dense: BTree<i32, i32> = BTree::new(33, true);
+        dense.bulk_load((0..n as i32).map(|k| (k, k)), 1.0).unwrap();
+        assert!(fill_factor(&dense, n, 33) > 0.95);
+        assert!(fill_factor(&sparse, n, 33) < 0.7);
+    }
+
+    #[test]
+    fn test_bulk_load_rejects() {
+        let mut bt: BTree<i32, i32> = BTree::new(5, true);
+        let unsorted = (0..100).chain(50..60).map(|k| (k, k));
+        assert!(matches!(
+            bt.bulk_load(unsorted, 1.0),
+            Err(BTreeError::Unsorted)
+        ));
+        let dups = (0..100).chain(99..100).map(|k| (k, k));
+        assert!(matches!(
+            bt.bulk_load(dups, 1.0),
+            Err(BTreeError::DuplicateKey)
+        ));
+        // nothing was loaded and the pages were given back
+        assert_eq!(bt.find_range(&0, &100).unwrap().len(), 0);
+        let header = bt.header();
+        assert_eq!(header.next_id - 2, header.free_count);
+
+        bt.insert(1, 1).unwrap();
+        let err = bt.bulk_load([(2, 2)], 1.0);
+        assert!(matches!(err, Err(BTreeError::NotEmpty)));
+
+        let mut bt: BTree<i32, i32> = BTree::new(5, false);
+        bt.bulk_load((0..100).flat_map(|k| [(k, 0), (k, 1)]), 1.0)
+            .unwrap();
+        bt.assert_valid();
+        assert_eq!(bt.delete(&42).unwrap(), 2);
+        assert_eq!(bt.find_range(&0, &100).unwrap().len(), 198);
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut bt: BTree<i32, i32> = BTree::new(5, true);
+        let mut cursor = bt.cursor();
+        assert!(!cursor.first().unwrap());
+        assert!(!cursor.last().unwrap());
+        assert!(!cursor.seek(&0).unwrap());
+        assert_eq!(cursor.key(), None);
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut keys: Vec<i32> = (0..1000).map(|k| 2 * k).collect();
+        keys.shuffle(&mut rng);
+        for &k in keys.iter() {
+            bt.insert(k, -k).unwrap();
+        }
+        // a mix of removed and soft deleted entries
+        for k in (0..2000).step_by(6) {
+            bt.delete(&k).unwrap();
+        }
+        bt.set_soft_delete(true);
+        for k in (4..2000).step_by(12) {
+            bt.delete(&k).unwrap();
+        }
+        let live: Vec<i32> = (0..2000)
+            .step_by(2)
+            .filter(|k| k % 6 != 0 && (k - 4) % 12 != 0)
+            .collect();
+
+        let mut cursor = bt.cursor();
+        let mut fwd = vec![];
+        let mut valid = cursor.first().unwrap();
+        while valid {
+            assert_eq!(cursor.val(), Some(&-cursor.key().unwrap()));
+            fwd.push(*cursor.key().unwrap());
+            valid = cursor.next().unwrap();
+        }
+        assert_eq!(fwd, live);
+        assert!(!cursor.next().unwrap());
+
+        let mut bwd = vec![];
+        let mut valid = cursor.last().unwrap();
+        while valid {
+            bwd.push(*cursor.key().unwrap());
+            valid = cursor.prev().unwrap();
+        }
+        bwd.reverse();
+        assert_eq!(bwd, live);
+
+        // seek lands on the first live key >= the target
+        for target in [-5, 0, 1, 2, 4, 5, 1000, 1997, 1998, 1999] {
+            let valid = cursor.seek(&target).unwrap();
+            let expected = live.iter().find(|&&k| k >= target);
+            assert_eq!(valid, expected.is_some());
+            assert_eq!(cursor.key(), expected);
+        }
+
+        // stepping back and forth across leaf boundaries
+        cursor.seek(&1000).unwrap();
+        let pos = live.iter().position(|&k| k >= 1000).unwrap();
+        for i in 1..100 {
+            assert!(cursor.prev().unwrap());
+            assert_eq!(cursor.key(), Some(&live[pos - i]));
+        }
+        for i in 1..200 {
+            assert!(cursor.next().unwrap());
+            assert_eq!(cursor.key(), Some(&live[pos - 99 + i]));
+        }
+    }
+
+    #[test]
+    fn test_find_range_rev() {
+        let path = tmp_db("range-rev");
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut keys: Vec<i32> = (0..2000).collect();
+        keys.shuffle(&mut rng);
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 7, true).unwrap();
+            for &k in keys.iter() {
+                bt.insert(k, 3 * k).unwrap();
+            }
+            // merges relink the leaves too
+            for &k in keys[..700].iter() {
+                bt.delete(&k).unwrap();
+            }
+            bt.assert_valid();
+        }
+
+        // reverse scans and cursors walk back through the left sibling links
+        // read from the file, which assert_valid checks
+        let bt: BTree<i32, i32> = BTree::open(&path, 7, true).unwrap();
+        bt.assert_valid();
+        for (min, max) in [(0, 2000), (-10, 5), (51, 1300), (1990, 3000), (7, 7)] {
+            let mut expected = bt.find_range(&min, &max).unwrap();
+            expected.reverse();
+            assert_eq!(bt.find_range_rev(&min, &max).unwrap(), expected);
+        }
+
+        let mut cursor = bt.cursor();
+        for target in [-1, 0, 500, 1999, 2500] {
+            let valid = cursor.seek_rev(&target).unwrap();
+            let expected = bt.find_range_rev(&i32::MIN, &target).unwrap();
+            assert_eq!(valid, !expected.is_empty());
+            assert_eq!(cursor.key(), expected.first().map(|(k, _)| k));
+        }
+        drop(bt);
+        remove_db(&path);
+
+        // runs of duplicates spanning leaves
+        let mut bt: BTree<i32, i32> = BTree::new(5, false);
+        for k in 0..30 {
+            for v in 0..k % 7 {
+                bt.insert(k, v).unwrap();
+            }
+        }
+        bt.assert_valid();
+        let mut expected = bt.find_range(&3, &20).unwrap();
+        expected.reverse();
+        assert_eq!(bt.find_range_rev(&3, &20).unwrap(), expected);
+        let mut cursor = bt.cursor();
+        assert!(cursor.seek_rev(&20).unwrap());
+        assert_eq!(cursor.key(), Some(&20));
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.key(), Some(&22));
+    }
+
+    fn row(k: i32, len: usize) -> Vec<Value> {
+        let name = format!("{}-", k).repeat(len + 1);
+        vec![
+            Value::VarChar(VarChar::new(&name[..len])),
+            Value::VarChar(VarChar::new(&name[..len / 2])),
+        ]
+    }
+
+    #[test]
+    fn test_overflow_pages() {
+        let path = tmp_db("overflow");
+        let mut rng = StdRng::seed_from_u64(13);
+        let lens: Vec<usize> = (0..300)
+            .map(|_| rng.gen_range(0..=VARCHAR_MAX_LEN as usize))
+            .collect();
+        {
+            let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 101, true).unwrap();
+            for (k, &len) in lens.iter().enumerate() {
+                bt.insert(k as i32, row(k as i32, len)).unwrap();
+            }
+        }
+
+        let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 101, true).unwrap();
+        for (k, &len) in lens.iter().enumerate() {
+            assert_eq!(bt.find(&(k as i32)).unwrap(), Some(row(k as i32, len)));
+        }
+        // deleting rows frees their overflow pages, which are reused
+        for k in (0..300).step_by(2) {
+            bt.delete(&k).unwrap();
+        }
+        let free_count = bt.header().free_count;
+        assert!(free_count > 0);
+        let next_id = bt.header().next_id;
+        for k in (0..300).step_by(2) {
+            bt.insert(k, row(k, lens[k as usize])).unwrap();
+        }
+        // the freed pages are used up before the file grows, which it only
+        // does for leaves that split as the rows go back in
+        assert_eq!(bt.header().free_count, 0);
+        assert!(bt.header().next_id - next_id < free_count);
+        bt.rebuild().unwrap();
+        bt.assert_valid();
+        drop(bt);
+
+        let bt: BTree<i32, Vec<Value>> = BTree::open(&path, 101, true).unwrap();
+        let rows = bt.find_range(&0, &300).unwrap();
+        assert_eq!(rows.len(), 300);
+        for (k, v) in rows {
+            assert_eq!(v, row(k, lens[k as usize]));
+        }
+        drop(bt);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_overflow_chain() {
+        // vals spanning several overflow pages
+        let big = |k: i32| -> Vec<Value> {
+            (0..20)
+                .map(|i| Value::VarChar(VarChar::new(&format!("{}-{}", k, i).repeat(1000))))
+                .collect()
+        };
+        let path = tmp_db("overflow-chain");
+        {
+            let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 5, true).unwrap();
+            for k in 0..10 {
+                bt.insert(k, big(k)).unwrap();
+            }
+            bt.set_soft_delete(true);
+            bt.delete(&3).unwrap();
+        }
+        let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 5, true).unwrap();
+        assert_eq!(bt.find(&3).unwrap(), None);
+        // replacing a soft deleted val frees its chain
+        bt.insert(3, big(30)).unwrap();
+        for k in 0..10 {
+            let expected = if k == 3 { big(30) } else { big(k) };
+            assert_eq!(bt.find(&k).unwrap(), Some(expected));
+        }
+        drop(bt);
+        remove_db(&path);
+
+        let mut bt: BTree<i32, Vec<Value>> = BTree::new(5, true);
+        bt.bulk_load((0..10).map(|k| (k, big(k))), 1.0).unwrap();
+        assert_eq!(bt.find(&7).unwrap(), Some(big(7)));
+    }
+
+    #[test]
+    fn test_split_by_size() {
+        let mut rng = StdRng::seed_from_u64(14);
+        let b = 4095;
+        let n = 2000;
+        let mut keys: Vec<i32> = (0..n).collect();
+        keys.shuffle(&mut rng);
+
+        // wide rows split pages long before the fanout is reached
+        let lens: Vec<usize> = (0..n).map(|_| rng.gen_range(500..3000)).collect();
+        let mut bt: BTree<i32, Vec<Value>> = BTree::new(b, true);
+        for &k in keys.iter() {
+            bt.insert(k, row(k, lens[k as usize])).unwrap();
+        }
+        bt.assert_valid();
+        assert!(bt.header().depth > 0);
+        for &k in keys[..n as usize / 2].iter() {
+            bt.delete(&k).unwrap();
+        }
+        bt.assert_valid();
+        for &k in keys[n as usize / 2..].iter() {
+            assert_eq!(bt.find(&k).unwrap(), Some(row(k, lens[k as usize])));
+        }
+
+        let mut bulk: BTree<i32, Vec<Value>> = BTree::new(b, true);
+        bulk.bulk_load((0..n).map(|k| (k, row(k, lens[k as usize]))), 1.0)
+            .unwrap();
+        bulk.assert_valid();
+        assert_eq!(bulk.find(&7).unwrap(), Some(row(7, lens[7])));
+
+        // narrow rows pack many more entries into a page
+        let mut bt: BTree<i32, Vec<Value>> = BTree::new(b, true);
+        for &k in keys[..n as usize / 2].iter() {
+            bt.insert(k, row(k, 8)).unwrap();
+        }
+        bt.assert_valid();
+        assert_eq!(bt.header().depth, 0);
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let mut rng = StdRng::seed_from_u64(15);
+        let n = 3000;
+        let mut keys: Vec<VarChar> = (0..n)
+            .map(|i| {
+                let len = rng.gen_range(1..4000);
+                VarChar::new(&format!("{}:{}", i, "x".repeat(len)))
+            })
+            .collect();
+        keys.shuffle(&mut rng);
+        let path = tmp_db("string-keys");
+        {
+            let mut bt: BTree<VarChar, i32> = BTree::open(&path, 4095, true).unwrap();
+            for (i, k) in keys.iter().enumerate() {
+                bt.insert(k.clone(), i as i32).unwrap();
+            }
+            bt.assert_valid();
+            // the separators are truncated to a few bytes, so a single
+            // interior page holds them all however long the keys are
+            assert_eq!(bt.header().depth, 1);
+        }
+
+        let mut bt: BTree<VarChar, i32> = BTree::open(&path, 4095, true).unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(bt.find(k).unwrap(), Some(i as i32));
+        }
+        let mut sorted = keys.clone();
+        sorted.sort();
+        let (min, max) = (&sorted[0], &sorted[n - 1]);
+        let found: Vec<VarChar> = bt
+            .find_range(min, max)
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(found, sorted);
+
+        for k in keys[..n / 2].iter() {
+            bt.delete(k).unwrap();
+        }
+        bt.assert_valid();
+        bt.rebuild().unwrap();
+        bt.assert_valid();
+        drop(bt);
+        let bt: BTree<VarChar, i32> = BTree::open(&path, 4095, true).unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            let expected = if i < n / 2 { None } else { Some(i as i32) };
+            assert_eq!(bt.find(k).unwrap(), expected);
+        }
+        drop(bt);
+        remove_db(&path);
+
+        let mut bt: BTree<String, i32> = BTree::new(5, true);
+        let huge = "x".repeat(MAX_KEY_SIZE);
+        assert!(matches!(bt.insert(huge, 0), Err(BTreeError::KeyTooLarge)));
+    }
+
+    #[test]
+    fn test_key_compression() {
+        let path = tmp_db("key-compression");
+        let key = |i: i32| format!("https://example.com/users/{:06}/profile", i);
+        let n = 2000;
+        {
+            let mut bt: BTree<String, i32> = BTree::open(&path, 255, true).unwrap();
+            for i in (0..n).rev() {
+                bt.insert(key(i), i).unwrap();
+            }
+            bt.assert_valid();
+            let stats = bt.key_stats().unwrap();
+            // leaves share most of each key, and separators need only the
+            // bytes up to where neighbouring keys differ
+            assert!(stats.prefix_saved > stats.leaf_bytes);
+            assert!(stats.separator_bytes > 0);
+            assert!(stats.separator_saved > 0);
+        }
+
+        let mut bt: BTree<String, i32> = BTree::open(&path, 255, true).unwrap();
+        for i in 0..n {
+            assert_eq!(bt.find(&key(i)).unwrap(), Some(i));
+        }
+        // keys that share less of the prefix grow the leaves they land in
+        for i in 0..n / 10 {
+            bt.insert(format!("{}{}", "z".repeat(i as usize % 100), i), i)
+                .unwrap();
+        }
+        bt.assert_valid();
+        for i in (0..n).step_by(2) {
+            bt.delete(&key(i)).unwrap();
+        }
+        bt.assert_valid();
+        drop(bt);
+        let bt: BTree<String, i32> = BTree::open(&path, 255, true).unwrap();
+        for i in 0..n {
+            let expected = if i % 2 == 0 { None } else { Some(i) };
+            assert_eq!(bt.find(&key(i)).unwrap(), expected);
+        }
+        drop(bt);
+        remove_db(&path);
+
+        // fixed size keys are stored whole
+        let mut bt: BTree<i32, i32> = BTree::new(5, true);
+        for i in 0..100 {
+            bt.insert(i, i).unwrap();
+        }
+        let stats = bt.key_stats().unwrap();
+        assert_eq!(stats.prefix_saved, 0);
+        assert_eq!(stats.separator_saved, 0);
+    }
 
-            // since we inserted one entry, we can garbage collect one entry
-            let mut del_idx = page
-                .deleted
-                .iter()
-                .rev()
-                .enumerate()
-                .filter(|&(_, &b)| b)
-                .map(|(i, _)| i);
-            if let Some(i) = del_idx.next() {
-                page.deleted.remove(i);
-                page.keys.remove(i);
-                page.vals.remove(i);
-            }
-
-            let mut needs_split = page.keys.len() >= self.b;
-            if !needs_split {
-                self.pager.write_page(&page);
-                Ok(())
-            } else {
-                let mut par_id_opt = visited.pop();
-                // TODO: OVERFLOW BORKEN
-                // try to overflow to sibling first
-                // if page.sibling.is_some() && par_id_opt.is_some() {
-                //     let par_id = par_id_opt.unwrap();
-                //     let sib_id = page.sibling.unwrap();
-                //     let mut parent = self.pager.read_page(par_id).unwrap().clone();
-                //     let mut sibling = self.pager.read_page(sib_id).unwrap().clone();
-
-                //     if let Ok(()) = self.overflow_to_sibling(&mut page, &mut sibling, &mut parent) {
-                //         self.pager.write_page(&page);
-                //         self.pager.write_page(&sibling);
-                //         self.pager.write_page(&parent);
-                //         return Ok(());
-                //     }
-                // }
-                // split page and propagate split upward if necessary
-                let max_splits = self.depth + 1;
-                for _ in 0..max_splits {
-                    match par_id_opt {
-                        Some(par_id) => {
-                            let mut parent = self.pager.read_page(par_id).unwrap().clone();
-                            let sibling = self.split_page(&mut page, &mut parent);
-                            self.pager.write_page(&page);
-                            self.pager.write_page(&sibling);
-
-                            needs_split = parent.keys.len() >= self.b;
-                            if !needs_split {
-                                self.pager.write_page(&parent);
-                                break;
-                            } else {
-                                // loop
-                                page = parent;
-                                par_id_opt = visited.pop();
-                            }
-                        }
-                        None => {
-                            // split root
-                            assert_eq!(self.root_id, page.id);
-                            let (sibling, root) = self.split_root(&mut page);
-
-                            self.pager.write_page(&page);
-                            self.pager.write_page(&sibling);
-                            self.pager.write_page(&root);
-                            break;
-                        }
-                    }
-                }
-                Ok(())
+    #[test]
+    fn test_order_statistics() {
+        let mut rng = StdRng::seed_from_u64(17);
+        let n = 3000;
+        let mut keys: Vec<i32> = (0..n).map(|k| 2 * k).collect();
+        keys.shuffle(&mut rng);
+        let path = tmp_db("order-statistics");
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+            for &k in keys.iter() {
+                bt.insert(k, -k).unwrap();
             }
+            bt.assert_valid();
+            assert_eq!(bt.count().unwrap(), n as usize);
         }
 
-        // Attempt to overflow keys & vals of the leaf page to its right sibling.
-        // Returns Err if overflow not possible.
-        // fn overflow_to_sibling(
-        //     &mut self,
-        //     page: &mut Page<K, V>,
-        //     sibling: &mut Page<K, V>,
-        //     parent: &mut Page<K, V>,
-        // ) -> Result<(), &'static str> {
-        //     let p = page.keys.len();
-        //     let s = sibling.keys.len();
-        //     // how many keys to overflow from page?
-        //     let mov = {
-        //         // p > s + 1 ensures we can move at least one key to sibling
-        //         // p + s < b ensures we don't underflow
-        //         if s >= self.b || p <= s + 1 || p + s < self.b {
-        //             return Err("not attempting overflow");
-        //         }
-        //         (p - s) / 2
-        //     };
-
-        //     // move data to sibling
-        //     // TODO: insert(0,-) is inefficient
-        //     for k in page.keys.drain((p - 1 - mov)..) {
-        //         sibling.keys.insert(0, k);
-        //     }
-        //     for v in page.vals.drain((p - 1 - mov)..) {
-        //         sibling.vals.insert(0, v);
-        //     }
-        //     for d in page.deleted.drain((p - 1 - mov)..) {
-        //         sibling.deleted.insert(0, d);
-        //     }
-
-        //     // update parent key
-        //     let max_key = page.keys.last().unwrap().clone();
-        //     let idx = parent.find(&max_key);
-        //     parent.keys[idx] = max_key;
-
-        //     Ok(())
-        // }
+        let mut bt: BTree<i32, i32> = BTree::open(&path, 5, true).unwrap();
+        assert_eq!(bt.rank(&0).unwrap(), 0);
+        assert_eq!(bt.rank(&7).unwrap(), 4);
+        assert_eq!(bt.rank(&(2 * n)).unwrap(), n as usize);
+        assert_eq!(bt.nth(0).unwrap(), Some((0, 0)));
+        assert_eq!(bt.nth(1234).unwrap(), Some((2468, -2468)));
+        assert_eq!(bt.nth(n as usize).unwrap(), None);
+        assert_eq!(bt.count_range(&10, &20).unwrap(), 6);
+        assert_eq!(bt.count_range(&11, &11).unwrap(), 0);
+        assert_eq!(bt.count_range(&20, &10).unwrap(), 0);
 
-        // Split the given page into two and promote a key its parent page.
-        // Mutates the page and parent and return the new right sibling.
-        fn split_page(&mut self, page: &mut Page<K, V>, parent: &mut Page<K, V>) -> Page<K, V> {
-            //println!("{} {}", page.keys.len(), self.b);
-            assert!(page.keys.len() >= self.b);
-            let split_idx = self.b / 2;
-            let split_key = page.keys[split_idx].clone();
-            // allocate right child page. the current page becomes left child page
-            let sibling = self.divide_page(page);
+        // counts follow deletes, soft deletes and reinserts
+        for &k in keys[..n as usize / 2].iter() {
+            bt.delete(&k).unwrap();
+        }
+        bt.assert_valid();
+        bt.set_soft_delete(true);
+        for &k in keys[n as usize / 2..n as usize * 3 / 4].iter() {
+            bt.delete(&k).unwrap();
+        }
+        bt.assert_valid();
+        bt.insert(keys[n as usize / 2], 0).unwrap();
+        bt.assert_valid();
+        let mut live: Vec<i32> = keys[n as usize * 3 / 4..].to_vec();
+        live.push(keys[n as usize / 2]);
+        live.sort();
+        assert_eq!(bt.count().unwrap(), live.len());
+        for (i, k) in live.iter().enumerate().step_by(37) {
+            assert_eq!(bt.rank(k).unwrap(), i);
+            assert_eq!(bt.nth(i).unwrap().map(|(k, _)| k), Some(*k));
+        }
+        let (min, max) = (live[10], live[live.len() - 10]);
+        let expected = bt.find_range(&min, &max).unwrap().len();
+        assert_eq!(bt.count_range(&min, &max).unwrap(), expected);
+        bt.rebuild().unwrap();
+        bt.assert_valid();
+        assert_eq!(bt.count().unwrap(), live.len());
+        drop(bt);
+        remove_db(&path);
 
-            // insert left and right as parent's children
-            let idx = parent.find(&split_key);
-            parent.keys.insert(idx, split_key);
-            parent.children.insert(idx, page.id);
-            parent.children[idx + 1] = sibling.id;
+        // duplicate runs are counted across leaves
+        let mut bt: BTree<i32, i32> = BTree::new(5, false);
+        for i in 0..100 {
+            bt.insert(i % 3, i).unwrap();
+        }
+        bt.assert_valid();
+        assert_eq!(bt.count_range(&1, &1).unwrap(), 33);
+        assert_eq!(bt.rank(&2).unwrap(), 67);
+    }
 
-            sibling
+    #[test]
+    fn test_range_ops() {
+        let mut rng = StdRng::seed_from_u64(18);
+        let n = 2000;
+        let mut keys: Vec<i32> = (0..n).collect();
+        keys.shuffle(&mut rng);
+        let mut bt: BTree<i32, Vec<Value>> = BTree::new(4095, true);
+        for &k in keys.iter() {
+            bt.insert(k, row(k, 100)).unwrap();
         }
+        let depth = bt.header().depth;
 
-        // Splits a page without a parent i.e. the root page.
-        // In this case a new root page is created along with the right sibling page.
-        // Returns (sibling , new root).
-        fn split_root(&mut self, page: &mut Page<K, V>) -> (Page<K, V>, Page<K, V>) {
-            let split_idx = self.b / 2;
-            let split_key = page.keys[split_idx].clone();
-            let sibling = self.divide_page(page);
-            // current page was the root page; create a new root
-            let new_root: Page<K, V> = Page {
-                id: self.next_id as u32,
-                keys: vec![split_key],
-                children: vec![page.id, sibling.id],
-                vals: Vec::new(),
-                ptype: PageType::Interior,
-                sibling: None,
-                deleted: vec![],
-            };
-            self.next_id += 1;
-            self.root_id = new_root.id;
-            self.depth += 1;
-            (sibling, new_root)
+        // growing the vals splits the leaves they're in
+        let n_updated = bt
+            .update_range(&500, &999, |&k, v| *v = row(k, 3000))
+            .unwrap();
+        assert_eq!(n_updated, 500);
+        bt.assert_valid();
+        assert!(bt.header().depth >= depth);
+        // and spills vals too large for a leaf
+        bt.update_range(&700, &709, |&k, v| *v = row(k, PAGE_SIZE))
+            .unwrap();
+        bt.assert_valid();
+        // shrinking them merges the leaves again
+        bt.update_range(&600, &899, |&k, v| *v = row(k, 10))
+            .unwrap();
+        bt.assert_valid();
+        let expected_len = |k: i32| match k {
+            600..=899 => 10,
+            500..=999 => 3000,
+            _ => 100,
+        };
+        for k in (0..n).step_by(7) {
+            assert_eq!(bt.find(&k).unwrap(), Some(row(k, expected_len(k))));
         }
 
-        // Helper function for page splitting: divide upper half of page into
-        // a new (right) sibling and returns the sibling.
-        fn divide_page(&mut self, page: &mut Page<K, V>) -> Page<K, V> {
-            let split_idx = self.b / 2;
+        assert_eq!(bt.delete_range(&100, &1899).unwrap(), 1800);
+        bt.assert_valid();
+        assert_eq!(bt.count().unwrap(), 200);
+        assert_eq!(bt.delete_range(&100, &1899).unwrap(), 0);
+        assert_eq!(bt.delete_range(&50, &10).unwrap(), 0);
+        let found: Vec<i32> = bt
+            .find_range(&0, &n)
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(found, (0..100).chain(1900..n).collect::<Vec<_>>());
 
-            // allocate right child page. the current page becomes left child page
-            let mut r_page = Page {
-                id: self.next_id as u32,
-                keys: Vec::with_capacity(split_idx),
-                vals: Vec::with_capacity(split_idx),
-                deleted: Vec::with_capacity(split_idx),
-                children: vec![],
-                ptype: page.ptype.clone(),
-                sibling: page.sibling,
-            };
-            self.next_id += 1;
-            r_page.keys = page.keys.drain((split_idx + 1)..).collect();
+        // soft deleted entries are neither updated nor deleted again
+        bt.set_soft_delete(true);
+        assert_eq!(bt.delete_range(&0, &49).unwrap(), 50);
+        assert_eq!(bt.update_range(&0, &99, |_, _| {}).unwrap(), 50);
+        assert_eq!(bt.delete_range(&0, &99).unwrap(), 50);
+        bt.assert_valid();
+        assert_eq!(bt.count().unwrap(), 100);
 
-            if page.ptype == PageType::Leaf {
-                r_page.vals = page.vals.drain((split_idx + 1)..).collect();
-                r_page.deleted = page.deleted.drain((split_idx + 1)..).collect();
-                page.sibling = Some(r_page.id);
-            } else {
-                r_page.children = page.children.drain((split_idx + 1)..).collect();
-            }
-            r_page
+        // duplicate runs are updated across leaves
+        let mut bt: BTree<i32, i32> = BTree::new(5, false);
+        for i in 0..300 {
+            bt.insert(i % 3, i).unwrap();
         }
+        assert_eq!(bt.update_range(&1, &1, |_, v| *v = -*v).unwrap(), 100);
+        let vals: Vec<i32> = bt
+            .find_range(&1, &1)
+            .unwrap()
+            .iter()
+            .map(|(_, v)| *v)
+            .collect();
+        assert!(vals.iter().all(|&v| v < 0 && v % 3 == -1));
+        assert_eq!(bt.delete_range(&1, &2).unwrap(), 200);
+        bt.assert_valid();
+        assert_eq!(bt.count().unwrap(), 100);
 
-        fn find_leaf(&self, key: &K) -> u32 {
-            let mut id = self.root_id;
-            for _ in 0..self.depth {
-                let page = self.pager.read_page(id).unwrap();
-                let idx = page.find(key);
-                id = page.children[idx];
+        // wide ranges empty runs of leaves and interior pages at once
+        let mut bt: BTree<i32, i32> = BTree::new(5, false);
+        let mut model = vec![];
+        for i in 0..3000 {
+            let k = rng.gen_range(0..1000);
+            bt.insert(k, i).unwrap();
+            model.push(k);
+        }
+        for _ in 0..40 {
+            let min = rng.gen_range(0..1000);
+            let max = min + rng.gen_range(0..150);
+            let n = model.iter().filter(|&&k| min <= k && k <= max).count();
+            model.retain(|&k| k < min || k > max);
+            assert_eq!(bt.delete_range(&min, &max).unwrap(), n);
+            bt.assert_valid();
+            assert_eq!(bt.count().unwrap(), model.len());
+        }
+        bt.delete_range(&0, &1000).unwrap();
+        bt.assert_valid();
+        assert_eq!(bt.header().depth, 0);
+    }
+
+    #[test]
+    fn test_upsert() {
+        let path = tmp_db("upsert");
+        {
+            let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+            for k in 0..500 {
+                assert_eq!(bt.upsert(k, row(k, 10)).unwrap(), None);
             }
-            id
+            assert_eq!(bt.upsert(7, row(7, 20)).unwrap(), Some(row(7, 10)));
+            assert_eq!(bt.update(&8, row(8, 3000)).unwrap(), row(8, 10));
+            assert!(matches!(
+                bt.update(&500, row(500, 10)),
+                Err(BTreeError::KeyNotFound)
+            ));
+            // modified vals may grow past what a leaf holds
+            let n = bt
+                .modify(&9, |v| {
+                    v[0] = Value::VarChar(VarChar::new(&"x".repeat(PAGE_SIZE)))
+                })
+                .unwrap();
+            assert_eq!(n, 1);
+            assert!(matches!(
+                bt.modify(&-1, |_| {}),
+                Err(BTreeError::KeyNotFound)
+            ));
+            bt.assert_valid();
         }
 
-        // Mark entries associatied with key as deleted
-        pub fn delete(&mut self, key: &K) -> Result<usize, KeyNotFoundError> {
-            let mut id = self.find_leaf(key);
-            let mut n_deleted = 0;
+        let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        assert_eq!(bt.find(&7).unwrap(), Some(row(7, 20)));
+        assert_eq!(bt.find(&8).unwrap(), Some(row(8, 3000)));
+        assert_eq!(
+            bt.find(&9).unwrap().unwrap()[0].to_string().len(),
+            PAGE_SIZE
+        );
+        assert_eq!(bt.count().unwrap(), 500);
 
-            'outer: loop {
-                let mut leaf = self.pager.read_page(id).unwrap().clone();
-                let idx = leaf.find(key);
-                for i in idx..leaf.deleted.len() {
-                    if leaf.keys[i] != *key {
-                        break 'outer;
-                    }
-                    leaf.deleted[i] = true;
-                    n_deleted += 1;
-                    self.pager.write_page(&leaf);
-                }
-                // we may have to search the siblings
-                match leaf.sibling {
-                    Some(sid) => {
-                        id = sid;
-                    }
-                    None => {
-                        break;
-                    }
-                }
-            }
-            if n_deleted > 0 {
-                Ok(n_deleted)
-            } else {
-                Err(KeyNotFoundError)
+        // soft deleted keys aren't updated, but are upserted in place
+        bt.set_soft_delete(true);
+        bt.delete(&10).unwrap();
+        assert!(matches!(
+            bt.update(&10, row(10, 5)),
+            Err(BTreeError::KeyNotFound)
+        ));
+        assert_eq!(bt.upsert(10, row(10, 5)).unwrap(), None);
+        assert_eq!(bt.find(&10).unwrap(), Some(row(10, 5)));
+        assert_eq!(bt.count().unwrap(), 500);
+        bt.assert_valid();
+        drop(bt);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_non_unique() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let path = tmp_db("non-unique");
+        let mut model: Vec<Vec<i32>> = vec![vec![]; 20];
+        {
+            let mut bt: BTree<i32, i32> = BTree::open(&path, 5, false).unwrap();
+            // long runs of a few keys span many leaves
+            for i in 0..3000 {
+                let k = if rng.gen_bool(0.8) {
+                    rng.gen_range(0..4) * 5
+                } else {
+                    rng.gen_range(0..20)
+                };
+                bt.insert(k, i).unwrap();
+                model[k as usize].push(i);
             }
+            bt.assert_valid();
         }
 
-        // traverse page IDs in level order
-        fn traverse(&self) -> Vec<Vec<u32>> {
-            let mut lvl = 0;
-            let mut ids = vec![vec![self.root_id]];
-            let mut q = VecDeque::from([(0, self.root_id)]);
-            let max_loop = self.next_id;
-            for _ in 0..=max_loop {
-                match q.pop_front() {
-                    Some((l, id)) => {
-                        if l >= lvl {
-                            lvl += 1;
-                            ids.push(vec![]);
-                        }
-                        let page = self.pager.read_page(id).unwrap();
-                        if page.ptype == PageType::Leaf {
-                            continue;
-                        }
-                        for cid in page.children.iter() {
-                            ids[lvl].push(cid.clone());
-                            q.push_back((l + 1, cid.clone()));
-                        }
-                    }
-                    None => {
-                        break;
-                    }
+        let mut bt: BTree<i32, i32> = BTree::open(&path, 5, false).unwrap();
+        let sorted = |mut vs: Vec<i32>| {
+            vs.sort();
+            vs
+        };
+        for (k, vs) in model.iter().enumerate() {
+            let k = k as i32;
+            assert_eq!(sorted(bt.find_all(&k).unwrap()), sorted(vs.clone()));
+            assert_eq!(bt.find(&k).unwrap().is_some(), !vs.is_empty());
+            assert_eq!(bt.count_range(&k, &k).unwrap(), vs.len());
+        }
+
+        // delete single pairs out of the runs, hard and soft
+        for round in 0..2 {
+            bt.set_soft_delete(round == 1);
+            for vs in model.iter_mut() {
+                vs.shuffle(&mut rng);
+            }
+            for (k, vs) in model.iter_mut().enumerate() {
+                let n = vs.len() / 3;
+                for v in vs.drain(..n) {
+                    bt.delete_entry(&(k as i32), &v).unwrap();
                 }
             }
-            ids.pop();
-            ids
+            bt.assert_valid();
+        }
+        assert!(matches!(
+            bt.delete_entry(&5, &-1),
+            Err(BTreeError::KeyNotFound)
+        ));
+        for (k, vs) in model.iter().enumerate() {
+            assert_eq!(
+                sorted(bt.find_all(&(k as i32)).unwrap()),
+                sorted(vs.clone())
+            );
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::btree::*;
-    use rand::prelude::*;
+        // a run whose first leaves were emptied is still found
+        let first: Vec<i32> = model[10].clone();
+        for v in first.iter().take(first.len() - 1) {
+            bt.delete_entry(&10, v).unwrap();
+        }
+        assert_eq!(bt.find(&10).unwrap(), first.last().copied());
+        assert_eq!(bt.delete(&10).unwrap(), 1);
+        assert_eq!(bt.find(&10).unwrap(), None);
+        bt.assert_valid();
+        drop(bt);
+        remove_db(&path);
+    }
 
     #[test]
-    fn test_insert_no_split() {
-        let mut bt: BTree<i32, i32> = BTree::new(27, false);
-        let kvs = [(5, 50), (6, 60), (7, -70), (7, 70), (8, 80)];
-        for (k, v) in kvs.into_iter() {
-            let err = bt.insert(k, v);
-            assert!(err.is_ok());
+    fn test_transactions() {
+        let path = tmp_db("transactions");
+        {
+            let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+            for k in 0..1000 {
+                bt.insert(k, row(k, 10)).unwrap();
+            }
+            bt.flush().unwrap();
+            let before = bt.find_range(&i32::MIN, &i32::MAX).unwrap();
+            let next_id = bt.header().next_id;
+
+            // evictions during the transaction write pages that are undone
+            bt.set_pool_capacity(4).unwrap();
+            bt.begin().unwrap();
+            assert!(bt.in_transaction());
+            assert!(matches!(bt.begin(), Err(BTreeError::TransactionOpen)));
+            for k in 1000..3000 {
+                bt.insert(k, row(k, 10)).unwrap();
+            }
+            bt.delete_range(&100, &400).unwrap();
+            bt.update(&5, row(5, PAGE_SIZE)).unwrap();
+            bt.update(&6, row(6, 10)).unwrap();
+            bt.assert_valid();
+            bt.rollback().unwrap();
+
+            assert!(!bt.in_transaction());
+            assert_eq!(bt.find_range(&i32::MIN, &i32::MAX).unwrap(), before);
+            assert_eq!(bt.count().unwrap(), 1000);
+            assert_eq!(bt.header().next_id, next_id);
+            bt.assert_valid();
+            assert!(matches!(bt.rollback(), Err(BTreeError::NoTransaction)));
+            assert!(matches!(bt.commit(), Err(BTreeError::NoTransaction)));
+
+            // committed changes persist, and the overflow pages the transaction
+            // released are freed once it commits
+            bt.begin().unwrap();
+            bt.insert(1000, row(1000, 20)).unwrap();
+            bt.update(&7, row(7, PAGE_SIZE)).unwrap();
+            // the spilled version is only seen by the transaction, so it's
+            // dropped when it's replaced
+            bt.update(&7, row(7, 10)).unwrap();
+            bt.delete(&7).unwrap();
+            let free_count = bt.header().free_count;
+            bt.commit().unwrap();
+            assert!(bt.header().free_count > free_count);
+        }
+
+        let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        assert_eq!(bt.find(&1000).unwrap(), Some(row(1000, 20)));
+        assert_eq!(bt.find(&7).unwrap(), None);
+        assert_eq!(bt.count().unwrap(), 1000);
+
+        // a transaction that is never committed is lost on a crash
+        bt.begin().unwrap();
+        for k in 2000..2500 {
+            bt.insert(k, row(k, 10)).unwrap();
         }
-        assert_eq!(bt.find(&5), Some(50));
-        assert_eq!(bt.find(&6), Some(60));
-        assert_eq!(bt.find(&8), Some(80));
+        bt.delete(&0).unwrap();
+        std::mem::forget(bt);
+
+        let bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        assert_eq!(bt.find(&0).unwrap(), Some(row(0, 10)));
+        assert_eq!(bt.find(&2000).unwrap(), None);
+        assert_eq!(bt.count().unwrap(), 1000);
+        bt.assert_valid();
+        drop(bt);
+        remove_db(&path);
     }
 
     #[test]
-    fn test_insert_split() {
-        let mut bt: BTree<i32, i32> = BTree::new(3, true);
-        let kvs = [(5, 55), (6, 66), (7, 77), (8, 88), (9, 99), (10, 100)];
-        for (k, v) in kvs.into_iter() {
-            let err = bt.insert(k, v);
-            assert!(err.is_ok());
+    fn test_savepoints() {
+        let mut rng = StdRng::seed_from_u64(22);
+        let path = tmp_db("savepoints");
+        let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        let mut model = std::collections::BTreeMap::new();
+        for k in 0..500 {
+            bt.insert(k, row(k, 10)).unwrap();
+            model.insert(k, row(k, 10));
+        }
+        assert!(matches!(bt.savepoint("a"), Err(BTreeError::NoTransaction)));
+
+        bt.set_pool_capacity(4).unwrap();
+        bt.begin().unwrap();
+        // the model as it was at each savepoint
+        let mut saved = vec![];
+        for round in 0..40 {
+            match rng.gen_range(0..4) {
+                0 => {
+                    let name = format!("sp{}", saved.len());
+                    bt.savepoint(&name).unwrap();
+                    saved.push(model.clone());
+                }
+                1 if !saved.is_empty() => {
+                    let i = rng.gen_range(0..saved.len());
+                    bt.rollback_to(&format!("sp{}", i)).unwrap();
+                    saved.truncate(i + 1);
+                    model = saved[i].clone();
+                }
+                2 if !saved.is_empty() => {
+                    let i = rng.gen_range(0..saved.len());
+                    bt.release(&format!("sp{}", i)).unwrap();
+                    saved.truncate(i);
+                }
+                _ => {
+                    // a batch of changes, some with spilled vals
+                    for _ in 0..100 {
+                        let k = rng.gen_range(0..2000);
+                        let len = if rng.gen_bool(0.05) {
+                            PAGE_SIZE
+                        } else {
+                            10 + round
+                        };
+                        if rng.gen_bool(0.3) {
+                            let _ = bt.delete(&k);
+                            model.remove(&k);
+                        } else {
+                            let old = bt.upsert(k, row(k, len)).unwrap();
+                            assert_eq!(old, model.insert(k, row(k, len)));
+                        }
+                    }
+                }
+            }
+            assert_eq!(bt.count().unwrap(), model.len());
         }
+        bt.assert_valid();
+        let rows = bt.find_range(&i32::MIN, &i32::MAX).unwrap();
+        assert_eq!(rows, model.clone().into_iter().collect::<Vec<_>>());
+        assert!(matches!(
+            bt.rollback_to("missing"),
+            Err(BTreeError::NoSavepoint(_))
+        ));
+        bt.commit().unwrap();
+        drop(bt);
 
-        assert_eq!(bt.find(&5), Some(55));
-        assert_eq!(bt.find(&6), Some(66));
-        assert_eq!(bt.find(&7), Some(77));
-        assert_eq!(bt.find(&9), Some(99));
-        assert_eq!(bt.find(&8), Some(88));
-        assert_eq!(bt.find(&666), None);
+        let bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        assert_eq!(bt.find_range(&i32::MIN, &i32::MAX).unwrap(), rows);
+        bt.assert_valid();
+        drop(bt);
+        remove_db(&path);
     }
 
     #[test]
-    fn test_insert_rand() {
-        let mut rng = rand::thread_rng();
-        let sizes = [33, 101, 179, 213, 303];
-        for size in sizes.into_iter() {
-            println!("size={}", size);
-            let mut bt: BTree<i32, i32> = BTree::new(size, true);
-            let mut keys: Vec<i32> = (0..50000)
-                .map(|_| {
-                    let k = rng.gen::<i32>();
-                    return k;
-                })
-                .collect();
-            keys.sort();
-            keys.dedup();
-            let mut vals = vec![];
-            for &k in keys.iter() {
-                let val: i32 = rng.gen();
-                vals.push(val);
-                let err = bt.insert(k, val);
-                assert!(err.is_ok());
+    fn test_snapshots() {
+        let mut rng = StdRng::seed_from_u64(23);
+        let path = tmp_db("snapshots");
+        let mut bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        let mut model = std::collections::BTreeMap::new();
+        for k in 0..1000 {
+            bt.insert(k, row(k, 10)).unwrap();
+            model.insert(k, row(k, 10));
+        }
+        let scan = |bt: &BTree<i32, Vec<Value>>, snap: &Snapshot| {
+            bt.find_range_at(&i32::MIN, &i32::MAX, snap).unwrap()
+        };
+
+        // each open snapshot with the rows it should see
+        let mut snaps = vec![];
+        for round in 0..40 {
+            match rng.gen_range(0..6) {
+                0 | 1 => {
+                    let rows: Vec<_> = model.clone().into_iter().collect();
+                    snaps.push((bt.snapshot(), rows));
+                }
+                2 if !snaps.is_empty() => {
+                    let (snap, _) = snaps.remove(rng.gen_range(0..snaps.len()));
+                    bt.release_snapshot(snap);
+                }
+                3 => {
+                    bt.rebuild().unwrap();
+                }
+                _ => {
+                    // a batch of changes, some in a transaction that may be
+                    // rolled back, with snapshots taken while it's open
+                    let txn = rng.gen_bool(0.5);
+                    let saved = model.clone();
+                    if txn {
+                        bt.begin().unwrap();
+                    }
+                    for i in 0..100 {
+                        let k = rng.gen_range(0..1500);
+                        let len = if rng.gen_bool(0.05) {
+                            PAGE_SIZE
+                        } else {
+                            10 + round
+                        };
+                        if rng.gen_bool(0.3) {
+                            let _ = bt.delete(&k);
+                            model.remove(&k);
+                        } else {
+                            bt.upsert(k, row(k, len)).unwrap();
+                            model.insert(k, row(k, len));
+                        }
+                        if txn && i == 50 {
+                            let rows = saved.clone().into_iter().collect();
+                            snaps.push((bt.snapshot(), rows));
+                        }
+                    }
+                    if txn && rng.gen_bool(0.3) {
+                        bt.rollback().unwrap();
+                        model = saved;
+                    } else if txn {
+                        bt.commit().unwrap();
+                    }
+                }
             }
-            for (k, v) in keys.iter().zip(vals) {
-                assert_eq!(bt.find(k), Some(v));
+            assert_eq!(bt.count().unwrap(), model.len());
+            for (snap, rows) in snaps.iter() {
+                assert_eq!(scan(&bt, snap), *rows);
             }
         }
+        bt.assert_valid();
+        let rows: Vec<_> = model.into_iter().collect();
+        assert_eq!(bt.find_range(&i32::MIN, &i32::MAX).unwrap(), rows);
+        for (snap, snap_rows) in snaps.iter() {
+            let (k, v) = &snap_rows[snap_rows.len() / 2];
+            assert_eq!(bt.find_at(k, snap).unwrap().as_ref(), Some(v));
+        }
+
+        // compaction keeps the versions the open snapshots see, and drops
+        // them once they're released
+        let last = bt.snapshot();
+        bt.delete_range(&0, &499).unwrap();
+        bt.rebuild().unwrap();
+        assert_eq!(scan(&bt, &last), rows);
+        for (snap, snap_rows) in snaps.iter() {
+            assert_eq!(scan(&bt, snap), *snap_rows);
+        }
+        let leaf_bytes = bt.key_stats().unwrap().leaf_bytes;
+        bt.release_snapshot(last);
+        for (snap, _) in snaps {
+            bt.release_snapshot(snap);
+        }
+        bt.rebuild().unwrap();
+        assert!(bt.key_stats().unwrap().leaf_bytes < leaf_bytes);
+        bt.assert_valid();
+        drop(bt);
+
+        let rows: Vec<_> = rows.into_iter().filter(|(k, _)| *k >= 500).collect();
+        let bt: BTree<i32, Vec<Value>> = BTree::open(&path, 4095, true).unwrap();
+        assert_eq!(bt.find_range(&i32::MIN, &i32::MAX).unwrap(), rows);
+        drop(bt);
+        remove_db(&path);
     }
 
     #[test]
-    fn test_delete_rand() {
-        let sizes = [71, 155, 191, 211, 301];
-        for size in sizes.into_iter() {
-            println!("size={}", size);
-            let mut rng = rand::thread_rng();
-            let mut bt: BTree<i32, i32> = BTree::new(size, true);
-            let n = 50000;
-            let mut keys: Vec<i32> = (0..n)
-                .map(|_| {
-                    let k = rng.gen::<i32>();
-                    return k;
-                })
-                .collect();
-            keys.sort();
-            keys.dedup();
-            let mut vals = vec![];
-            for &k in keys.iter() {
-                let val: i32 = rng.gen();
-                vals.push(val);
-                let err = bt.insert(k, val);
-                assert!(err.is_ok());
-            }
-            for k in keys[n / 2..].iter() {
-                let err = bt.delete(k);
-                assert!(err.is_ok());
+    fn test_shadow_pager() {
+        let path = tmp_db("shadow");
+        {
+            let mut bt: BTree<i32, i32> =
+                BTree::open_shadow(&path, 5, true, Retention::default()).unwrap();
+            for k in 0..100 {
+                assert!(bt.insert(k, k).is_ok());
             }
-            for (i, k) in keys[0..n / 2].iter().enumerate() {
-                assert_eq!(bt.find(k), Some(vals[i]));
+            bt.flush().unwrap();
+            // evictions write pages that are never committed
+            bt.set_pool_capacity(4).unwrap();
+            for k in 100..1000 {
+                assert!(bt.insert(k, k).is_ok());
             }
+            std::mem::forget(bt);
         }
-    }
+        assert!(!wal_path(&path).exists());
+        let mut bt: BTree<i32, i32> =
+            BTree::open_shadow(&path, 5, true, Retention::default()).unwrap();
+        assert_eq!(bt.find_range(&0, &1000).unwrap().len(), 100);
+        assert_eq!(bt.find(&500).unwrap(), None);
+        assert!(bt.insert(500, 5).is_ok());
+        drop(bt);
+        let bt: BTree<i32, i32> = BTree::open_shadow(&path, 5, true, Retention::default()).unwrap();
+        assert_eq!(bt.find(&500).unwrap(), Some(5));
+        let last = bt.commits().last().unwrap().id;
+        drop(bt);
 
-    #[test]
-    fn test_delete_one() {
-        let mut bt: BTree<i32, i32> = BTree::new(3, true);
-        let kvs = [(3, 333), (5, 55), (6, 66), (7, 77), (9, 99), (10, 100)];
-        for (k, v) in kvs.into_iter() {
-            let err = bt.insert(k, v);
-            assert!(err.is_ok());
+        // a torn write of the latest meta record leaves the commit before it
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[(last as usize + 1) % 2 * PAGE_SIZE / 2 + 13] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        let bt: BTree<i32, i32> = BTree::open_shadow(&path, 5, true, Retention::default()).unwrap();
+        assert_eq!(bt.find(&500).unwrap(), None);
+        assert_eq!(bt.find_range(&0, &1000).unwrap().len(), 100);
+        assert_eq!(bt.commits().last().unwrap().id, last - 1);
+        drop(bt);
+        remove_db(&path);
+
+        // a write copies the pages from the leaf up to the root, and leaves
+        // the pages of the last commit as they were
+        let mut bt: BTree<i32, i32> =
+            BTree::open_shadow(&path, 5, true, Retention::default()).unwrap();
+        for k in 0..1000 {
+            assert!(bt.insert(k * 2, k).is_ok());
         }
+        bt.flush().unwrap();
+        let (root, prev) = (bt.root_id(), bt.commits().last().unwrap().id);
+        let offset = root as usize * PAGE_SIZE;
+        let page = fs::read(&path).unwrap()[offset..(offset + PAGE_SIZE)].to_vec();
+        assert!(bt.insert(501, 0).is_ok());
+        assert_ne!(bt.root_id(), root);
+        bt.flush().unwrap();
+        bt.assert_valid();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[offset..(offset + PAGE_SIZE)], page);
+        drop(bt);
+        let old: BTree<i32, i32> = BTree::open_shadow_at(&path, prev).unwrap();
+        assert_eq!(old.root_id(), root);
+        assert_eq!(old.find(&501).unwrap(), None);
+        assert_eq!(old.find_range(&0, &2000).unwrap().len(), 1000);
+        drop(old);
+        remove_db(&path);
 
-        assert_eq!(bt.find(&5), Some(55));
-        let err = bt.delete(&5);
-        assert!(err.is_ok());
-        assert_eq!(err.unwrap(), 1 as usize);
-        assert_eq!(bt.find(&5), None);
+        // files with a log aren't copy-on-write files, and the other way round
+        drop(BTree::<i32, i32>::open(&path, 5, true).unwrap());
+        let res = BTree::<i32, i32>::open_shadow(&path, 5, true, Retention::default());
+        assert!(matches!(
+            res,
+            Err(BTreeError::Pager(PagerError::NotCopyOnWrite))
+        ));
+        remove_db(&path);
+        drop(BTree::<i32, i32>::open_shadow(&path, 5, true, Retention::default()).unwrap());
+        let res = BTree::<i32, i32>::open(&path, 5, true);
+        assert!(matches!(
+            res,
+            Err(BTreeError::Pager(PagerError::CopyOnWrite))
+        ));
+        remove_db(&path);
     }
 
     #[test]
-    fn test_duplicate_key() {
-        let mut bt: BTree<i32, i32> = BTree::new(5, true);
-        assert!(bt.insert(5, 55).is_ok());
-        let err = bt.insert(5, 555);
-        assert_eq!(err, Err(DuplicateKeyError));
+    fn test_shadow_commits() {
+        let mut rng = StdRng::seed_from_u64(24);
+        let path = tmp_db("shadow-commits");
+        let mut bt: BTree<i32, i32> =
+            BTree::open_shadow(&path, 5, true, Retention::default()).unwrap();
+        let mut model = std::collections::BTreeMap::new();
+        let mut round = |bt: &mut BTree<i32, i32>, model: &mut std::collections::BTreeMap<_, _>| {
+            for _ in 0..50 {
+                let k = rng.gen_range(0..300);
+                if rng.gen_bool(0.3) {
+                    if model.remove(&k).is_some() {
+                        bt.delete(&k).unwrap();
+                    }
+                } else {
+                    let v = rng.gen_range(0..1000);
+                    bt.upsert(k, v).unwrap();
+                    model.insert(k, v);
+                }
+            }
+            bt.flush().unwrap();
+        };
 
-        assert!(bt.delete(&5).is_ok());
-        assert!(bt.insert(5, 555).is_ok());
-    }
+        // the model as it was at each commit
+        let mut states = vec![];
+        for _ in 0..30 {
+            round(&mut bt, &mut model);
+            states.push((bt.commits().last().unwrap().id, model.clone()));
+        }
+        let commits: Vec<u64> = bt.commits().iter().map(|c| c.id).collect();
+        assert_eq!(commits.len(), Retention::default().commits);
+        for (id, state) in states {
+            let res = BTree::<i32, i32>::open_shadow_at(&path, id);
+            if !commits.contains(&id) {
+                assert!(matches!(res, Err(BTreeError::Pager(PagerError::NoCommit(n))) if n == id));
+                continue;
+            }
+            let old = res.unwrap();
+            let rows = old.find_range(&0, &300).unwrap();
+            assert_eq!(rows, state.into_iter().collect::<Vec<_>>());
+            old.assert_valid();
+        }
 
-    // #[test]
-    // fn test_rebuild() {
-    //     let mut bt: BTree<i32, i32> = BTree::new(3, false);
-    //     let kvs = [
-    //         (5, 55),
-    //         (6, 66),
-    //         (7, 77),
-    //         (9, 99),
-    //         (9, 999),
-    //         (9, 9999),
-    //         (10, 100),
-    //         (3, 333),
-    //     ];
-    //     for (k, v) in kvs.into_iter() {
-    //         let err = bt.insert(k, v);
-    //         assert!(err.is_ok());
-    //     }
-    //     assert!(bt.delete(&5).is_ok());
-    //     assert!(bt.delete(&9).is_ok());
-    //     assert!(bt.delete(&7).is_ok());
-
-    //     bt.rebuild();
-
-    //     assert_eq!(bt.find(&9), None);
-    //     assert_eq!(bt.find(&5), None);
-    //     assert_eq!(bt.find(&7), None);
-    //     assert_eq!(bt.find(&10), Some(100));
-    //     assert_eq!(bt.find(&3), Some(333));
-    //     assert_eq!(bt.find(&6), Some(66));
-    // }
+        // old commits are read-only
+        let mut old: BTree<i32, i32> = BTree::open_shadow_at(&path, commits[0]).unwrap();
+        old.insert(1000, 0).unwrap();
+        assert!(matches!(
+            old.flush(),
+            Err(BTreeError::Pager(PagerError::ReadOnly))
+        ));
+        drop(old);
 
-    #[test]
-    fn test_find_range() {
-        let mut bt: BTree<i32, i32> = BTree::new(33, true);
-        for i in (0..10000).step_by(3) {
-            let err = bt.insert(i as i32, 3 * i as i32);
-            assert!(err.is_ok());
+        // the pages of commits no longer retained are reused, so the file
+        // stops growing
+        let len = fs::metadata(&path).unwrap().len();
+        for _ in 0..30 {
+            round(&mut bt, &mut model);
         }
+        assert!(fs::metadata(&path).unwrap().len() < len * 3 / 2);
+        drop(bt);
 
-        let min = 51;
-        let max = 300;
-        let kvs = bt.find_range(&min, &max);
-        assert_eq!(51, kvs.first().unwrap().0);
-        assert_eq!(300, kvs.last().unwrap().0);
-        assert_eq!(kvs.len(), ((max - min) / 3 + 1) as usize);
-
-        for (k, v) in kvs.into_iter() {
-            assert!(k >= 51);
-            assert!(300 >= k);
-            assert_eq!(v, 3 * k);
+        // commits made in the last hour are all kept, then only the last two
+        let recent = Retention {
+            commits: 1,
+            secs: 3600,
+        };
+        let mut bt: BTree<i32, i32> = BTree::open_shadow(&path, 5, true, recent).unwrap();
+        let rows = bt.find_range(&0, &300).unwrap();
+        assert_eq!(rows, model.clone().into_iter().collect::<Vec<_>>());
+        for _ in 0..5 {
+            round(&mut bt, &mut model);
         }
+        let commits = bt.commits();
+        assert_eq!(commits.len(), Retention::default().commits + 5);
+        assert!(commits.windows(2).all(|w| w[0].time <= w[1].time));
+        drop(bt);
+        let last_two = Retention {
+            commits: 2,
+            secs: 0,
+        };
+        let mut bt: BTree<i32, i32> = BTree::open_shadow(&path, 5, true, last_two).unwrap();
+        round(&mut bt, &mut model);
+        assert_eq!(bt.commits().len(), 2);
+        drop(bt);
+        remove_db(&path);
     }
 
     #[test]
-    fn test_pack_bits() {
-        // 01101001 11000001 10110100 001
-        let bits = vec![
-            false, true, true, false, true, false, false, true, true, true, false, false, false,
-            false, false, true, true, false, true, true, false, true, false, false, false, false,
-            true,
-        ];
-        let len = bits.len();
-
-        let bytes = pack_bits(&bits);
-        assert_eq!(bytes.len(), 4);
-
-        let unpacked = unpack_bits(len, &bytes[..]);
-        for i in 0..bits.len() {
-            assert_eq!(bits[i], unpacked[i]);
+    fn test_composite_keys() {
+        let path = tmp_db("composite-keys");
+        let key = |i: i32| (VarChar::new(&format!("user{}", i % 7)), i);
+        {
+            let mut bt: BTree<(VarChar, i32), String> = BTree::open(&path, 5, true).unwrap();
+            for i in 0..500 {
+                bt.insert(key(i), format!("row {}", i)).unwrap();
+            }
+            bt.assert_valid();
+        }
+        let bt: BTree<(VarChar, i32), String> = BTree::open(&path, 5, true).unwrap();
+        for i in 0..500 {
+            assert_eq!(bt.find(&key(i)).unwrap(), Some(format!("row {}", i)));
         }
+        // keys sort by their first field, then their second
+        let user3 = VarChar::new("user3");
+        let rows = bt
+            .find_range(&(user3.clone(), i32::MIN), &(user3, i32::MAX))
+            .unwrap();
+        let ids: Vec<i32> = rows.iter().map(|((_, i), _)| *i).collect();
+        assert_eq!(ids, (0..500).filter(|i| i % 7 == 3).collect::<Vec<_>>());
+        drop(bt);
+        remove_db(&path);
     }
 }
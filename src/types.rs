@@ -10,6 +10,8 @@ pub mod values {
         InvalidUtf8(#[from] std::string::FromUtf8Error),
         #[error("invalid byte length")]
         InvalidByteLen,
+        #[error("invalid type id {0}")]
+        InvalidType(u8),
     }
 
     pub trait Serializable {
@@ -37,7 +39,7 @@ pub mod values {
         DateTime(DateTime),
     }
 
-    #[derive(Debug, Eq, PartialEq, Clone)]
+    #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone)]
     pub struct VarChar {
         val: String,
         max_len: u32,
@@ -81,6 +83,10 @@ pub mod values {
                 max_len: VARCHAR_MAX_LEN,
             }
         }
+
+        pub fn as_str(&self) -> &str {
+            &self.val
+        }
     }
 
     impl Serializable for i32 {
@@ -109,23 +115,76 @@ pub mod values {
             bs
         }
         fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
+            if bs.len() < 4 {
+                return Err(SerializeError::InvalidByteLen);
+            }
             let len_bytes: [u8; 4] = bs[0..4].try_into().unwrap();
             let len = u32::from_le_bytes(len_bytes);
             let size = 4 + (len as usize);
+            if bs.len() < size {
+                return Err(SerializeError::InvalidByteLen);
+            }
             let val = String::from_utf8(bs[4..size].to_vec())?;
-            Ok((size, VarChar { val, max_len: 0 }))
+            Ok((
+                size,
+                VarChar {
+                    val,
+                    max_len: VARCHAR_MAX_LEN,
+                },
+            ))
         }
         fn size(&self) -> usize {
             self.val.len() + 4
         }
     }
 
+    impl Serializable for String {
+        fn to_bytes(&self) -> Vec<u8> {
+            let l = u32::try_from(self.len()).unwrap();
+            let mut bs = l.to_le_bytes().to_vec();
+            bs.extend(self.as_bytes());
+            bs
+        }
+        fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
+            if bs.len() < 4 {
+                return Err(SerializeError::InvalidByteLen);
+            }
+            let len_bytes: [u8; 4] = bs[0..4].try_into().unwrap();
+            let size = 4 + u32::from_le_bytes(len_bytes) as usize;
+            if bs.len() < size {
+                return Err(SerializeError::InvalidByteLen);
+            }
+            Ok((size, String::from_utf8(bs[4..size].to_vec())?))
+        }
+        fn size(&self) -> usize {
+            self.len() + 4
+        }
+    }
+
+    // Pairs serialize as their fields one after the other, which makes
+    // composite keys such as (VarChar, i32).
+    impl<A: Serializable, B: Serializable> Serializable for (A, B) {
+        fn to_bytes(&self) -> Vec<u8> {
+            let mut bs = self.0.to_bytes();
+            bs.extend(self.1.to_bytes());
+            bs
+        }
+        fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
+            let (a_size, a) = A::from_bytes(bs)?;
+            let (b_size, b) = B::from_bytes(&bs[a_size..])?;
+            Ok((a_size + b_size, (a, b)))
+        }
+        fn size(&self) -> usize {
+            self.0.size() + self.1.size()
+        }
+    }
+
     impl Serializable for DateTime {
         fn to_bytes(&self) -> Vec<u8> {
             let date_enc = 10000 * self.year + 100 * self.month + self.day;
             let time_enc = self.hour * 10000 + self.minute * 100 + self.second;
-            let mut bs = u32::try_from(date_enc).unwrap().to_le_bytes().to_vec();
-            bs.extend(u32::try_from(time_enc).unwrap().to_le_bytes());
+            let mut bs = date_enc.to_le_bytes().to_vec();
+            bs.extend(time_enc.to_le_bytes());
             bs
         }
         fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
@@ -177,6 +236,18 @@ pub mod values {
         }
     }
 
+    impl TryFrom<u8> for Type {
+        type Error = SerializeError;
+        fn try_from(value: u8) -> Result<Self, Self::Error> {
+            match value {
+                _ if value == Type::Int as u8 => Ok(Type::Int),
+                _ if value == Type::VarChar as u8 => Ok(Type::VarChar),
+                _ if value == Type::DateTime as u8 => Ok(Type::DateTime),
+                _ => Err(SerializeError::InvalidType(value)),
+            }
+        }
+    }
+
     // have to dispatch the enum type... annoying
     impl Serializable for Value {
         fn to_bytes(&self) -> Vec<u8> {
@@ -190,8 +261,10 @@ pub mod values {
             v
         }
         fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
-            let type_id = bs[0] as usize;
-            let vtype = Type::try_from(type_id).unwrap();
+            if bs.is_empty() {
+                return Err(SerializeError::InvalidByteLen);
+            }
+            let vtype = Type::try_from(bs[0])?;
             let (size, val) = match vtype {
                 Type::Int => {
                     let (size, n) = i32::from_bytes(&bs[1..])?;
@@ -209,7 +282,8 @@ pub mod values {
             Ok((size + 1, val))
         }
         fn size(&self) -> usize {
-            match self {
+            // the type ID takes a byte
+            1 + match self {
                 Value::Int(n) => n.size(),
                 Value::VarChar(vc) => vc.size(),
                 Value::DateTime(dt) => dt.size(),
@@ -227,19 +301,22 @@ pub mod values {
             bs
         }
         fn from_bytes(bs: &[u8]) -> Result<(usize, Self), SerializeError> {
+            if bs.len() < 4 {
+                return Err(SerializeError::InvalidByteLen);
+            }
             let len_bytes: [u8; 4] = bs[0..4].try_into().unwrap();
             let len = u32::from_le_bytes(len_bytes) as usize;
             let mut vs = Vec::with_capacity(len);
             let mut j = 4;
-            for i in 0..len {
+            for _ in 0..len {
                 let (size, val) = Value::from_bytes(&bs[j..])?;
-                vs[i] = val;
+                vs.push(val);
                 j += size;
             }
             Ok((j, vs))
         }
         fn size(&self) -> usize {
-            self.to_bytes().len()
+            4 + self.iter().map(|v| v.size()).sum::<usize>()
         }
     }
 